@@ -11,27 +11,82 @@ INSERT INTO server (name, motd) VALUES ('Server', 'Welcome, fancy a game of Sea
 
 CREATE TABLE user (
         name VARCHAR(8) NOT NULL,
-        password_hash VARCHAR(74) NOT NULL DEFAULT '',
+        password_hash VARCHAR(128) NOT NULL DEFAULT '',
         display_name VARCHAR(32) NOT NULL,
         email_address VARCHAR(64) NOT NULL,
         admin BOOLEAN NOT NULL DEFAULT false,
         active BOOLEAN NOT NULL DEFAULT false,
         notify BOOLEAN NOT NULL DEFAULT true,
-        verification INT UNSIGNED NOT NULL DEFAULT 0,
-        new_password_hash VARCHAR(74) NOT NULL DEFAULT '',
+        token_generation INT UNSIGNED NOT NULL DEFAULT 0,
+        locale VARCHAR(5) NOT NULL DEFAULT 'en',
     PRIMARY KEY (name)
 );
 INSERT INTO user (name, display_name, email_address, notify, password_hash) VALUES ('self','Server', 'server@server.org', false, '$2b$10$lVkR4Xbo0BiOVts1QOw0w.dqTUSBOepeZbSxMNggY9KlOg1T5MTU6');
 INSERT INTO user (name, display_name, email_address, admin, active, notify, password_hash) VALUES ('admin','Administrator', 'admin@server.org', true, true, false, '$2b$10$lVkR4Xbo0BiOVts1QOw0w.dqTUSBOepeZbSxMNggY9KlOg1T5MTU6');
 
+CREATE TABLE verification (
+        user_name VARCHAR(8) NOT NULL,
+        purpose ENUM ('signup', 'password_change', 'password_reset', 'email_change') NOT NULL,
+        code INT UNSIGNED NOT NULL,
+        payload VARCHAR(128) NOT NULL DEFAULT '',
+        created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        expires TIMESTAMP NOT NULL,
+        attempts TINYINT UNSIGNED NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_name, purpose),
+    FOREIGN KEY (user_name) REFERENCES user(name)
+);
+
+CREATE TABLE lockout (
+        user_name VARCHAR(8) NOT NULL,
+        failures TINYINT UNSIGNED NOT NULL DEFAULT 0,
+        locked_until TIMESTAMP NULL,
+    PRIMARY KEY (user_name),
+    FOREIGN KEY (user_name) REFERENCES user(name)
+);
+
+CREATE TABLE role (
+        name VARCHAR(16) NOT NULL,
+    PRIMARY KEY (name)
+);
+INSERT INTO role (name) VALUES ('moderator'), ('organizer'), ('bot');
+
+CREATE TABLE role_permission (
+        role VARCHAR(16) NOT NULL,
+        permission ENUM ('set_motd', 'manage_games', 'manage_users', 'manage_admins', 'manage_rules', 'manage_server', 'manage_tournaments', 'unlimited_games') NOT NULL,
+    PRIMARY KEY (role, permission),
+    FOREIGN KEY (role) REFERENCES role(name)
+);
+INSERT INTO role_permission (role, permission) VALUES ('moderator', 'set_motd'), ('moderator', 'manage_games');
+INSERT INTO role_permission (role, permission) VALUES ('organizer', 'manage_tournaments'), ('organizer', 'unlimited_games');
+INSERT INTO role_permission (role, permission) VALUES ('bot', 'unlimited_games');
+
+CREATE TABLE user_role (
+        user_name VARCHAR(8) NOT NULL,
+        role VARCHAR(16) NOT NULL,
+    PRIMARY KEY (user_name, role),
+    FOREIGN KEY (user_name) REFERENCES user(name),
+    FOREIGN KEY (role) REFERENCES role(name)
+);
+
+CREATE TABLE refresh_token (
+        token_hash CHAR(64) NOT NULL,
+        user_name VARCHAR(8) NOT NULL,
+        generation INT UNSIGNED NOT NULL,
+        expires TIMESTAMP NOT NULL,
+    PRIMARY KEY (token_hash),
+    FOREIGN KEY (user_name) REFERENCES user(name)
+);
+
 CREATE TABLE game (
         id INT UNSIGNED NOT NULL auto_increment,
         status TINYINT UNSIGNED NOT NULL DEFAULT 0, 
-        board_size TINYINT UNSIGNED NOT NULL DEFAULT 8 CHECK (board_size >= 8 AND board_size <= 16),
+        board_size TINYINT UNSIGNED NOT NULL DEFAULT 8 CHECK (board_size <= 16),
         amount_of_players TINYINT UNSIGNED NOT NULL DEFAULT 1,
         placing TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
         started TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
         finished TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        difficulty TINYINT UNSIGNED NOT NULL DEFAULT 1,
+        rules BLOB NOT NULL,
     PRIMARY KEY (id)
 );
 
@@ -43,6 +98,7 @@ CREATE TABLE board (
         shots_fired INT UNSIGNED DEFAULT 0,
         shots_map VARBINARY(32),
         score INT UNSIGNED DEFAULT 0,
+        turn_shots TINYINT UNSIGNED NOT NULL DEFAULT 0,
     PRIMARY KEY (game_id, user_name),
     FOREIGN KEY (game_id) REFERENCES game(id),
     FOREIGN KEY (user_name) REFERENCES user(name)
@@ -63,7 +119,7 @@ INSERT INTO ship_class (name, size) VALUES ('Patrol Boat', 2);
 CREATE TABLE ship (
         game_id INT UNSIGNED NOT NULL,
         user_name VARCHAR(8) NOT NULL,
-        name VARCHAR(8) NOT NULL,
+        name VARCHAR(16) NOT NULL,
         class VARCHAR(16) NOT NULL,
         position_x TINYINT UNSIGNED NOT NULL,
         position_y TINYINT UNSIGNED NOT NULL,
@@ -75,3 +131,32 @@ CREATE TABLE ship (
     FOREIGN KEY (user_name) REFERENCES user(name),
     FOREIGN KEY (game_id, user_name) REFERENCES board(game_id, user_name)
 );
+
+CREATE TABLE notification (
+        user_name VARCHAR(8) NOT NULL,
+        game_id INT UNSIGNED NOT NULL,
+        event ENUM ('your_turn', 'player_joined', 'game_over', 'game_aborted') NOT NULL,
+        detail VARCHAR(255) NOT NULL DEFAULT '',
+        pending BOOLEAN NOT NULL DEFAULT true,
+        queued TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        sent TIMESTAMP NULL,
+    PRIMARY KEY (user_name, game_id, event),
+    FOREIGN KEY (user_name) REFERENCES user(name),
+    FOREIGN KEY (game_id) REFERENCES game(id)
+);
+
+CREATE TABLE job (
+        id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+        kind ENUM ('mail', 'ai_move', 'turn_timeout') NOT NULL,
+        game_id INT UNSIGNED,
+        payload TEXT NOT NULL,
+        status ENUM ('pending', 'running', 'dead') NOT NULL DEFAULT 'pending',
+        attempts TINYINT UNSIGNED NOT NULL DEFAULT 0,
+        run_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        last_error TEXT,
+        created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    INDEX (status, run_at),
+    INDEX (game_id),
+    FOREIGN KEY (game_id) REFERENCES game(id)
+);
@@ -0,0 +1,333 @@
+use sqlx::{MySql, MySqlPool, Transaction};
+use log::{error, info};
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+use bit_vec::BitVec;
+use crate::models::{game::*, board::*, ship::*};
+use crate::errors::CustomError;
+use crate::state_machine::{self, Shooter};
+use crate::controllers::board::shoot;
+use crate::hub::Hub;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// The computer opponent. The server plays as the special user 'self' (seeded in doc/schema.sql). It places a
+// random fleet when it joins and fires when the state machine hands it the turn, through an ai_move job. It only uses
+// what a human player knows: the cells it fired at, which of those were hits and which ships were sunk.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub const SERVER_USER: &str = "self";
+
+// What the server knows about a cell on the boards of its opponents
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Cell {
+    Unknown,
+    Miss,
+    Hit,
+    Sunk,
+}
+
+struct Knowledge {
+    board_size: u8,
+    cells: Vec<Cell>,
+    remaining: Vec<u8>,
+}
+
+// Add the server to a game and place its fleet. Returns the player id of the server and the first shooter when this
+// completed the game
+pub async fn join(tx: &mut Transaction<'_, MySql>, game: &Game) -> Result<(u8, Option<Shooter>), CustomError> {
+
+    let player_id = state_machine::add_player(tx, game, SERVER_USER).await?;
+
+    let fleet = random_fleet(&game.rules.fleet, game.board_size, game.rules.adjacency_allowed, &mut StdRng::from_entropy())
+        .ok_or(CustomError::InternalServerError)?;
+
+    for ship in fleet {
+        let sql = "INSERT INTO ship (game_id, user_name, name, class, position_x, position_y, direction) VALUES (?, ?, ?, ?, ?, ?, ?)";
+        sqlx::query(sql)
+            .bind(game.id)
+            .bind(SERVER_USER)
+            .bind(&ship.class)
+            .bind(&ship.class)
+            .bind(ship.x)
+            .bind(ship.y)
+            .bind(ship.direction)
+            .execute(&mut *tx)
+            .await
+            .map_err(|err| {
+                error!("Error placing ship for the server: {:?}", err);
+                CustomError::InternalServerError
+            })?;
+    }
+
+    info!("Server joined game {} as player {}", game.id, player_id);
+    let first = state_machine::placing_done(tx, game, player_id).await?;
+    Ok((player_id, first))
+}
+
+// Fire one shot for the server, run by the job workers. Nothing happens when it isn't the turn of the server anymore
+pub async fn take_turn(pool: &MySqlPool, hub: &Hub, game_id: u32, player_id: u8) -> Result<(), CustomError> {
+
+    let sql = "SELECT * FROM game WHERE id = ?";
+    let game: Game = sqlx::query_as(sql)
+        .bind(game_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching game {}: {:?}", game_id, err);
+            CustomError::InvalidGame
+        })?;
+
+    let sql = "SELECT * FROM board WHERE game_id = ? AND player_id = ?";
+    let board: Board = sqlx::query_as(sql)
+        .bind(game_id)
+        .bind(player_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching board of the server: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    if game.status != GameStatus::Active || board.status != BoardStatus::Shooting || board.user_name != SERVER_USER {
+        info!("Server has no turn to take in game {}", game_id);
+        return Ok(());
+    }
+
+    let sql = "SELECT ship.*, ship_class.size FROM ship INNER JOIN ship_class ON ship.class = ship_class.name WHERE ship.game_id = ? AND ship.user_name <> ?";
+    let ships: Vec<Ship> = sqlx::query_as(sql)
+        .bind(game_id)
+        .bind(SERVER_USER)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching ships: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let knowledge = Knowledge::new(game.board_size, &board.shots_map, &ships);
+    let (x, y) = knowledge.choose_shot(game.difficulty, &mut StdRng::from_entropy())
+        .ok_or(CustomError::InternalServerError)?;
+
+    // With salvo the server keeps the turn until all its shots are fired, the state machine queues the next shot
+    let result = shoot(pool, hub, game_id, player_id, SERVER_USER, Shot { x, y }).await?;
+    info!("Server fired at ({}, {}) in game {}: {:?}", x, y, game_id, result.outcome);
+    Ok(())
+}
+
+impl Knowledge {
+
+    // Only damage on cells the server fired at itself counts, the shots of other players are not known. So a ship
+    // is only known to be sunk when the server fired at every cell of it
+    fn new(board_size: u8, shots_map: &[u8], ships: &[Ship]) -> Self {
+
+        let fired = BitVec::from_bytes(shots_map);
+        let index = |(x, y): (u8, u8)| y as usize * board_size as usize + x as usize;
+        let mut cells: Vec<Cell> = (0..board_size as usize * board_size as usize)
+            .map(|index| if fired.get(index).unwrap_or(false) { Cell::Miss } else { Cell::Unknown })
+            .collect();
+        let mut remaining = Vec::new();
+
+        for ship in ships {
+            let ship_cells = ship.cells();
+            let known_sunk = ship.is_sunk() && ship_cells.iter().all(|cell| fired.get(index(*cell)).unwrap_or(false));
+            if !known_sunk {
+                remaining.push(ship.size);
+            }
+            for (bit, cell) in ship_cells.into_iter().enumerate() {
+                let index = index(cell);
+                if ship.damage & (1 << bit) == 0 || cells.get(index) == Some(&Cell::Unknown) {
+                    continue;
+                }
+                if !known_sunk {
+                    cells[index] = Cell::Hit;
+                } else if cells[index] != Cell::Hit {
+                    cells[index] = Cell::Sunk;
+                }
+            }
+        }
+
+        Knowledge { board_size, cells, remaining }
+    }
+
+    fn choose_shot(&self, difficulty: Difficulty, rng: &mut impl Rng) -> Option<(u8, u8)> {
+
+        let unknown: Vec<usize> = (0..self.cells.len()).filter(|index| self.cells[*index] == Cell::Unknown).collect();
+        let index = match difficulty {
+            Difficulty::Easy => *unknown.choose(rng)?,
+            Difficulty::Medium => self.target(rng).or_else(|| self.parity(&unknown).choose(rng).copied())?,
+            Difficulty::Hard => self.density(&unknown, rng)?,
+        };
+        Some(((index % self.board_size as usize) as u8, (index / self.board_size as usize) as u8))
+    }
+
+    // Target mode: continue a line of hits when there is one, otherwise try a neighbour of a hit
+    fn target(&self, rng: &mut impl Rng) -> Option<usize> {
+
+        let mut in_line = Vec::new();
+        let mut neighbours = Vec::new();
+
+        for index in (0..self.cells.len()).filter(|index| self.cells[*index] == Cell::Hit) {
+            for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                if let Some(next) = self.step(index, dx, dy) {
+                    if self.cells[next] == Cell::Unknown {
+                        neighbours.push(next);
+                    }
+                }
+                // Walk along a line of hits that ends in this hit
+                if self.step(index, -dx, -dy).map(|previous| self.cells[previous]) == Some(Cell::Hit) {
+                    let mut next = self.step(index, dx, dy);
+                    while let Some(cell) = next.filter(|cell| self.cells[*cell] == Cell::Hit) {
+                        next = self.step(cell, dx, dy);
+                    }
+                    if let Some(cell) = next.filter(|cell| self.cells[*cell] == Cell::Unknown) {
+                        in_line.push(cell);
+                    }
+                }
+            }
+        }
+
+        in_line.choose(rng).or_else(|| neighbours.choose(rng)).copied()
+    }
+
+    // Hunt mode: the smallest remaining ship always covers a cell where (x + y) is a multiple of its size, those
+    // cells are on the same diagonal as the top left corner
+    fn parity(&self, unknown: &[usize]) -> Vec<usize> {
+
+        let spacing = self.remaining.iter().min().copied().unwrap_or(2).max(2) as usize;
+        let size = self.board_size as usize;
+        let cells: Vec<usize> = unknown.iter().copied().filter(|index| {
+            let diagonal = (index % size + index / size) % spacing;
+            diagonal == 0
+        }).collect();
+        if cells.is_empty() { unknown.to_vec() } else { cells }
+    }
+
+    // Count for every cell in how many ways the remaining ships can cover it. Placements through a hit count
+    // much heavier, so the density doubles as target mode. Without hits only the parity cells are considered.
+    fn density(&self, unknown: &[usize], rng: &mut impl Rng) -> Option<usize> {
+
+        let size = self.board_size as usize;
+        let hunting = !self.cells.contains(&Cell::Hit);
+        let mut scores = vec![0u32; self.cells.len()];
+
+        for ship_size in &self.remaining {
+            for index in 0..self.cells.len() {
+                for direction in [Direction::East, Direction::South] {
+                    let cells = match ship_cells((index % size) as u8, (index / size) as u8, direction, *ship_size, self.board_size) {
+                        Some(cells) => cells,
+                        None => continue,
+                    };
+                    let indices: Vec<usize> = cells.iter().map(|(x, y)| *y as usize * size + *x as usize).collect();
+                    if indices.iter().any(|index| matches!(self.cells[*index], Cell::Miss | Cell::Sunk)) {
+                        continue;
+                    }
+                    let hits = indices.iter().filter(|index| self.cells[**index] == Cell::Hit).count() as u32;
+                    for index in indices {
+                        scores[index] += 1 + 20 * hits;
+                    }
+                }
+            }
+        }
+
+        let candidates = if hunting { self.parity(unknown) } else { unknown.to_vec() };
+        let best = candidates.iter().map(|index| scores[*index]).max()?;
+        let best_cells: Vec<usize> = candidates.into_iter().filter(|index| scores[*index] == best).collect();
+        best_cells.choose(rng).copied()
+    }
+
+    fn step(&self, index: usize, dx: i32, dy: i32) -> Option<usize> {
+
+        let size = self.board_size as i32;
+        let x = (index as i32 % size) + dx;
+        let y = (index as i32 / size) + dy;
+        if x < 0 || y < 0 || x >= size || y >= size {
+            return None;
+        }
+        Some((y * size + x) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ship(x: u8, y: u8, direction: Direction, size: u8, damage: u8) -> Ship {
+        Ship {
+            game_id: 1,
+            user_name: "alice".to_string(),
+            name: "Ship".to_string(),
+            class: "Ship".to_string(),
+            position_x: x,
+            position_y: y,
+            direction,
+            damage,
+            size,
+        }
+    }
+
+    fn fired(board_size: u8, cells: &[(u8, u8)]) -> Vec<u8> {
+        let mut map = BitVec::from_elem(board_size as usize * board_size as usize, false);
+        for (x, y) in cells {
+            map.set(*y as usize * board_size as usize + *x as usize, true);
+        }
+        map.to_bytes()
+    }
+
+    #[test]
+    fn only_own_sinks_are_known() {
+        // Sunk, but the server only fired at one cell: the other hit came from another player
+        let ships = [ship(0, 0, Direction::East, 2, 0b11), ship(0, 2, Direction::East, 3, 0b111)];
+        let knowledge = Knowledge::new(8, &fired(8, &[(0, 0), (0, 2), (1, 2), (2, 2)]), &ships);
+        assert_eq!(knowledge.remaining, vec![2]);
+        assert_eq!(knowledge.cells[0], Cell::Hit);
+        assert_eq!(knowledge.cells[1], Cell::Unknown);
+        assert_eq!(knowledge.cells[16], Cell::Sunk);
+    }
+
+    #[test]
+    fn targets_continue_a_line_of_hits() {
+        let ships = [ship(2, 2, Direction::East, 4, 0b0011)];
+        let knowledge = Knowledge::new(8, &fired(8, &[(2, 2), (3, 2)]), &ships);
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..10 {
+            let index = knowledge.target(&mut rng).unwrap();
+            assert!(index == 2 * 8 + 1 || index == 2 * 8 + 4);
+        }
+
+        // A single hit: try a neighbour
+        let ships = [ship(2, 2, Direction::East, 4, 0b0001)];
+        let knowledge = Knowledge::new(8, &fired(8, &[(2, 2)]), &ships);
+        let index = knowledge.target(&mut rng).unwrap();
+        assert!([2 * 8 + 1, 2 * 8 + 3, 8 + 2, 3 * 8 + 2].contains(&index));
+
+        // Nothing to target: hunt
+        let knowledge = Knowledge::new(8, &fired(8, &[(2, 2)]), &[ship(4, 4, Direction::East, 2, 0)]);
+        assert_eq!(knowledge.target(&mut rng), None);
+    }
+
+    #[test]
+    fn hunting_uses_the_smallest_remaining_ship() {
+        let knowledge = Knowledge::new(8, &fired(8, &[]), &[ship(0, 0, Direction::East, 3, 0), ship(0, 2, Direction::East, 4, 0)]);
+        let unknown: Vec<usize> = (0..64).collect();
+        let cells = knowledge.parity(&unknown);
+        assert_eq!(cells.len(), 21);
+        assert!(cells.iter().all(|index| (index % 8 + index / 8) % 3 == 0));
+
+        // Only cells that break the pattern are left
+        assert_eq!(knowledge.parity(&[1, 2]), vec![1, 2]);
+    }
+
+    #[test]
+    fn density_prefers_the_middle_and_hits() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let knowledge = Knowledge::new(8, &fired(8, &[]), &[ship(0, 0, Direction::East, 4, 0)]);
+        let unknown: Vec<usize> = (0..64).collect();
+        let index = knowledge.density(&unknown, &mut rng).unwrap();
+        let (x, y) = (index % 8, index / 8);
+        assert!((2..6).contains(&x) && (2..6).contains(&y));
+
+        // Between a hit and a miss there is only one way to go
+        let knowledge = Knowledge::new(8, &fired(8, &[(2, 2), (3, 2), (2, 3), (2, 1)]), &[ship(2, 2, Direction::West, 2, 0b01)]);
+        let unknown: Vec<usize> = (0..64).filter(|index| knowledge.cells[*index] == Cell::Unknown).collect();
+        assert_eq!(knowledge.density(&unknown, &mut rng), Some(2 * 8 + 1));
+    }
+}
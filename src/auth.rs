@@ -0,0 +1,177 @@
+use std::marker::PhantomData;
+use axum::{
+    async_trait, Extension, TypedHeader,
+    extract::FromRequestParts,
+    headers::{Authorization, authorization::Bearer},
+    http::request::Parts,
+};
+use sqlx::MySqlPool;
+use log::error;
+use serde::{Deserialize, Serialize};
+use jsonwebtoken::{decode, DecodingKey, Validation};
+use crate::models::{user::User, role::Permission};
+use crate::errors::CustomError;
+use crate::AppState;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Authentication of requests. Handlers declare what they need by taking an AuthUser (any logged in user) or a
+// Permitted<P> (a user with permission P) as argument. The Bearer token is decoded and checked against the user
+// table: the token is only accepted when its generation matches the token generation of the user, which is raised
+// on logout, deactivation or a change of role. The permissions are embedded in the token when it is issued, the
+// generation check guarantees they are still current. Admins always have every permission.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// The claims struct used for creating a Bearer token
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Claims {
+    pub sub: String,
+    pub iat: usize,
+    pub exp: usize,
+    pub admin: bool,
+    pub gen: u32,
+    pub permissions: Vec<Permission>,
+}
+
+// The permissions of a logged in user
+#[derive(Debug, Clone)]
+pub struct Permissions(pub Vec<Permission>);
+
+impl Permissions {
+    pub fn has(&self, permission: Permission) -> bool {
+        self.0.contains(&permission)
+    }
+
+    // Bail out with a forbidden error naming the permission when the user doesn't have it
+    pub fn require(&self, permission: Permission) -> Result<(), CustomError> {
+        if self.has(permission) {
+            Ok(())
+        } else {
+            error!("Permission {} missing, request denied", permission);
+            Err(CustomError::Forbidden(permission))
+        }
+    }
+}
+
+// A logged in and active user
+pub struct AuthUser(pub User, pub Permissions);
+
+// A logged in and active user with permission P, see the permit module for the available P's
+pub struct Permitted<P: Permit>(pub User, pub Permissions, pub PhantomData<P>);
+
+// Marker types that name a permission at the type level, so it can be required in the signature of a handler
+pub trait Permit {
+    const PERMISSION: Permission;
+}
+
+macro_rules! permits {
+    ($($permission:ident),*) => {
+        $(
+            pub struct $permission;
+            impl super::Permit for $permission {
+                const PERMISSION: super::Permission = super::Permission::$permission;
+            }
+        )*
+    };
+}
+
+// Only the permissions that are required by a handler signature have a marker, add one when needed
+pub mod permit {
+    permits!(SetMotd, ManageUsers, ManageRules, ManageServer);
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = CustomError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+
+        let TypedHeader(Authorization(bearer)) = TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                error!("No Bearer token: {:?}", err);
+                CustomError::InvalidToken
+            })?;
+
+        let Extension(pool) = Extension::<MySqlPool>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                error!("No database pool: {:?}", err);
+                CustomError::InternalServerError
+            })?;
+
+        authenticate(state, &pool, bearer.token()).await
+    }
+}
+
+// Check a Bearer token, also used for WebSockets where browsers can't send the Authorization header
+pub async fn authenticate(state: &AppState, pool: &MySqlPool, token: &str) -> Result<AuthUser, CustomError> {
+
+    // Decode the Bearer token
+    let claims = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map_err(|err| {
+        error!("Invalid token: {:?}", err.kind());
+        CustomError::InvalidToken
+    })?
+    .claims;
+
+    // Check the token against the current state of the user
+    let sql = "SELECT * FROM user WHERE name = ?";
+    let user: User = sqlx::query_as(sql)
+        .bind(&claims.sub)
+        .fetch_one(pool)
+        .await
+        .map_err(|err| {
+            error!("Error looking up user {} of token: {:?}", claims.sub, err);
+            CustomError::InvalidToken
+        })?;
+
+    if claims.gen != user.token_generation {
+        error!("Token of user {} is revoked", claims.sub);
+        return Err(CustomError::InvalidToken);
+    }
+
+    if !user.active {
+        error!("User {} is deactivated, request denied", claims.sub);
+        return Err(CustomError::UserDeactivated);
+    }
+
+    let permissions = if user.admin { Permission::ALL.to_vec() } else { claims.permissions };
+    Ok(AuthUser(user, Permissions(permissions)))
+}
+
+#[async_trait]
+impl<P: Permit> FromRequestParts<AppState> for Permitted<P> {
+    type Rejection = CustomError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+
+        let AuthUser(user, permissions) = AuthUser::from_request_parts(parts, state).await?;
+        permissions.require(P::PERMISSION)?;
+        Ok(Permitted(user, permissions, PhantomData))
+    }
+}
+
+// The permissions of a user, used when a token is issued
+pub async fn load_permissions(pool: &MySqlPool, user: &User) -> Result<Vec<Permission>, CustomError> {
+
+    if user.admin {
+        return Ok(Permission::ALL.to_vec());
+    }
+
+    let sql = "SELECT DISTINCT role_permission.permission FROM user_role \
+               INNER JOIN role_permission ON user_role.role = role_permission.role WHERE user_role.user_name = ?";
+    let permissions: Vec<(Permission,)> = sqlx::query_as(sql)
+        .bind(&user.name)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching permissions of user {}: {:?}", user.name, err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(permissions.into_iter().map(|(permission,)| permission).collect())
+}
@@ -1,20 +1,316 @@
-use std::fmt::Result;
-
 use axum::{
     Extension, Json, response::IntoResponse,
-    extract::{Path, TypedHeader, State},
-    headers::{Authorization, authorization::Bearer},
-    http::{StatusCode, header::{HeaderMap, HeaderValue, AUTHORIZATION}},
+    extract::{Path, State},
+    http::StatusCode,
 };
 //use axum_macros::debug_handler;
-use sqlx::{MySqlPool, mysql::MySqlQueryResult};
-use log::{debug, error, log_enabled, info, Level};
-use serde::{Deserialize, Serialize};
-use chrono::Utc;
+use sqlx::MySqlPool;
+use log::{error, info};
 use bit_vec::BitVec;
-use crate::models::board::*;
+use crate::models::{game::*, board::*, ship::*, event::GameEvent};
+use crate::hub::Hub;
 use crate::errors::CustomError;
+use crate::state_machine;
 
 use crate::AppState;
-use crate::check_access;
+use crate::auth::AuthUser;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handler for placing a ship on the board of a player. Every class in the fleet of the game rules must be placed
+//exactly once. When the fleet is complete the board transitions from placing to waiting.
+pub async fn place_ship(Path((game_id, player_id)): Path<(u32, u8)>,
+                        State(state): State<AppState>,
+                        Extension(pool): Extension<MySqlPool>,
+                        AuthUser(user, _): AuthUser,
+                        Json(new_ship): Json<NewShip>
+                        ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("Place ship request");
+
+    // Start transaction, the game is locked so concurrent placements in the same game are serialized
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error placing ship: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let game = state_machine::lock_game(&mut tx, game_id).await?;
+
+    // Check if the game is active, bail out if not
+    if game.status != GameStatus::Active {
+        return Err(CustomError::GameNotActive);
+    }
+
+    let sql = "SELECT * FROM board WHERE game_id = ? AND player_id = ? FOR UPDATE";
+    let board: Board = sqlx::query_as(sql)
+        .bind(game_id)
+        .bind(player_id)
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error fetching board of player {} in game {}: {:?}", player_id, game_id, err);
+            CustomError::InvalidPlayer
+        })?;
+
+    // Only the owner of a board can place ships on it
+    if board.user_name != user.name {
+        error!("User {} tried to place a ship on the board of {}", user.name, board.user_name);
+        return Err(CustomError::InvalidPlayer);
+    }
+
+    if board.status != BoardStatus::Placing {
+        return Err(CustomError::NotPlacing);
+    }
+
+    // The fleet that has to be placed
+    let classes = &game.rules.fleet;
+
+    let class = match classes.iter().find(|class| class.name == new_ship.class) {
+        Some(class) => class,
+        None => return Err(CustomError::UnknownShipClass),
+    };
+
+    // The ships already placed by this player
+    let sql = "SELECT ship.*, ship_class.size FROM ship INNER JOIN ship_class ON ship.class = ship_class.name WHERE ship.game_id = ? AND ship.user_name = ?";
+    let ships: Vec<Ship> = sqlx::query_as(sql)
+        .bind(game_id)
+        .bind(&user.name)
+        .fetch_all(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error fetching ships: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    if ships.iter().any(|ship| ship.class == class.name) {
+        return Err(CustomError::ShipPlaced);
+    }
+
+    // Check if the ship fits on the board and does not overlap with any other ship
+    let cells = ship_cells(new_ship.x, new_ship.y, new_ship.direction, class.size, game.board_size)
+        .ok_or(CustomError::ShipOutOfBounds)?;
+
+    if ships.iter().flat_map(|ship| ship.cells()).any(|cell| cells.contains(&cell)) {
+        return Err(CustomError::ShipOverlap);
+    }
+
+    if !game.rules.adjacency_allowed && ships.iter().any(|ship| touching(&cells, &ship.cells())) {
+        return Err(CustomError::ShipTouching);
+    }
+
+    // Add the ship. There is one ship per class so the class name is also the name of the ship
+    let sql = "INSERT INTO ship (game_id, user_name, name, class, position_x, position_y, direction) VALUES (?, ?, ?, ?, ?, ?, ?)";
+    sqlx::query(sql)
+        .bind(game_id)
+        .bind(&user.name)
+        .bind(&class.name)
+        .bind(&class.name)
+        .bind(new_ship.x)
+        .bind(new_ship.y)
+        .bind(new_ship.direction)
+        .execute(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error placing ship: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // When this was the last ship of the fleet, the player is done placing
+    let fleet_complete = ships.len() + 1 == classes.len();
+    let first = if fleet_complete {
+        state_machine::placing_done(&mut tx, &game, player_id).await?
+    } else {
+        None
+    };
+
+    // commit
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error placing ship: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    if fleet_complete {
+        state.hub.publish(game_id, &GameEvent::PlacingDone { player_id });
+    }
+    if let Some(first) = first {
+        state.hub.publish(game_id, &GameEvent::TurnChanged { player_id: first.player_id });
+    }
+
+    if fleet_complete {
+        Ok((StatusCode::CREATED, "Ship placed, fleet complete. Waiting for the other players"))
+    } else {
+        Ok((StatusCode::CREATED, "Ship placed"))
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handler for firing a shot. The shot is fired at the same cell on the boards of all opponents that did not lose
+//yet. The damage bitmap of every ship that is hit is updated, the cell is marked in the shots_map of the shooter
+//and the score of the shooter is increased with the number of hits.
+pub async fn fire_shot( Path((game_id, player_id)): Path<(u32, u8)>,
+                        State(state): State<AppState>,
+                        Extension(pool): Extension<MySqlPool>,
+                        AuthUser(user, _): AuthUser,
+                        Json(shot): Json<Shot>
+                        ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("Fire shot request");
+
+    let result = shoot(&pool, &state.hub, game_id, player_id, &user.name, shot).await?;
+
+    Ok((StatusCode::OK, Json(result)))
+}
+
+// Fire a shot from the board of player_id, owned by user_name. Used by the fire_shot handler and by the server when
+// it is playing itself. The result and what follows from it are published to the clients of the game.
+pub async fn shoot(pool: &MySqlPool, hub: &Hub, game_id: u32, player_id: u8, user_name: &str, shot: Shot) -> Result<ShotResult, CustomError> {
+
+    // Start transaction, the game and all its boards are locked while the shot is processed
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error firing shot: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let game = state_machine::lock_game(&mut tx, game_id).await?;
+
+    // Check if the game is active, bail out if not
+    if game.status != GameStatus::Active {
+        return Err(CustomError::GameNotActive);
+    }
+
+    let boards = state_machine::lock_boards(&mut tx, game_id).await?;
+
+    let board = boards.iter()
+        .find(|board| board.player_id == player_id)
+        .ok_or(CustomError::InvalidPlayer)?;
+
+    // Only the owner of a board can shoot from it, and only when it is his turn
+    if board.user_name != user_name {
+        error!("User {} tried to shoot from the board of {}", user_name, board.user_name);
+        return Err(CustomError::InvalidPlayer);
+    }
+
+    if board.status != BoardStatus::Shooting {
+        return Err(CustomError::NotYourTurn);
+    }
+
+    if shot.x >= game.board_size || shot.y >= game.board_size {
+        return Err(CustomError::ShotOutOfBounds);
+    }
+
+    // Check the shots_map of the shooter to reject a cell that was already fired at
+    let index = shot.y as usize * game.board_size as usize + shot.x as usize;
+    let mut shots_map = BitVec::from_bytes(&board.shots_map);
+    if shots_map.get(index).unwrap_or(false) {
+        return Err(CustomError::AlreadyShot);
+    }
+    shots_map.set(index, true);
+
+    // The ships of all opponents that are still in the game
+    let sql = "SELECT ship.*, ship_class.size FROM ship INNER JOIN ship_class ON ship.class = ship_class.name \
+               INNER JOIN board ON ship.game_id = board.game_id AND ship.user_name = board.user_name \
+               WHERE ship.game_id = ? AND ship.user_name <> ? AND board.status <> ? FOR UPDATE";
+    let mut ships: Vec<Ship> = sqlx::query_as(sql)
+        .bind(game_id)
+        .bind(user_name)
+        .bind(BoardStatus::Lost)
+        .fetch_all(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error fetching ships: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // Update the damage bitmap of every ship that covers the cell. A cell another player hit before is no new hit
+    let mut hit_ships = Vec::new();
+    for ship in ships.iter_mut() {
+        if ship.hit((shot.x, shot.y)) {
+
+            let sql = "UPDATE ship SET damage = ? WHERE game_id = ? AND user_name = ? AND name = ?";
+            sqlx::query(sql)
+                .bind(ship.damage)
+                .bind(game_id)
+                .bind(&ship.user_name)
+                .bind(&ship.name)
+                .execute(&mut tx)
+                .await
+                .map_err(|err| {
+                    error!("Error updating ship damage: {:?}", err);
+                    CustomError::InternalServerError
+                })?;
+
+            hit_ships.push((ship.user_name.clone(), ship.class.clone(), ship.is_sunk()));
+        }
+    }
+
+    let mut hits = Vec::new();
+    for (target, class, sunk) in hit_ships {
+        let target_board = boards.iter()
+            .find(|board| board.user_name == target)
+            .ok_or(CustomError::InternalServerError)?;
+        hits.push(Hit {
+            player_id: target_board.player_id,
+            fleet_sunk: ships.iter().filter(|ship| ship.user_name == target).all(|ship| ship.is_sunk()),
+            user_name: target,
+            outcome: if sunk { ShotOutcome::Sunk } else { ShotOutcome::Hit },
+            class: if sunk { Some(class) } else { None },
+        });
+    }
+
+    // Update the board of the shooter
+    let sql = "UPDATE board SET shots_map = ?, shots_fired = shots_fired + 1, turn_shots = turn_shots + 1, score = score + ? WHERE game_id = ? AND player_id = ?";
+    sqlx::query(sql)
+        .bind(shots_map.to_bytes())
+        .bind(hits.len() as u16)
+        .bind(game_id)
+        .bind(player_id)
+        .execute(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error updating board: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // Opponents without any ship left are eliminated, the state machine decides what happens next
+    let eliminated: Vec<u8> = hits.iter()
+        .filter(|hit| hit.fleet_sunk)
+        .map(|hit| hit.player_id)
+        .collect();
+    let next = state_machine::shot_fired(&mut tx, &game, player_id, &eliminated).await?;
+
+    // commit
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error firing shot: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let outcome = if hits.iter().any(|hit| hit.outcome == ShotOutcome::Sunk) {
+        ShotOutcome::Sunk
+    } else if hits.is_empty() {
+        ShotOutcome::Miss
+    } else {
+        ShotOutcome::Hit
+    };
+
+    let next_player = next.as_ref().map(|shooter| shooter.player_id);
+    let result = ShotResult { x: shot.x, y: shot.y, outcome, hits, next_player };
+
+    hub.publish(game_id, &GameEvent::ShotFired { player_id, result: result.clone() });
+    for eliminated in &eliminated {
+        hub.publish(game_id, &GameEvent::PlayerEliminated { player_id: *eliminated });
+    }
+    match next_player {
+        None => hub.publish(game_id, &GameEvent::GameFinished { status: GameStatus::Finished, winner: Some(player_id) }),
+        Some(next) if next != player_id => hub.publish(game_id, &GameEvent::TurnChanged { player_id: next }),
+        Some(_) => {},
+    }
 
+    Ok(result)
+}
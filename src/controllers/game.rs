@@ -1,58 +1,82 @@
 use axum::{
-    Extension, Json, response::IntoResponse,
-    extract::{Path, TypedHeader, State},
+    Extension, Json, TypedHeader, response::IntoResponse,
+    extract::{Path, Query, State, ws::{Message, WebSocket, WebSocketUpgrade}},
     headers::{Authorization, authorization::Bearer},
-    http::{StatusCode, header::{HeaderMap, HeaderValue, AUTHORIZATION}},
+    http::StatusCode,
 };
 //use axum_macros::debug_handler;
-use sqlx::{MySqlPool, mysql::MySqlQueryResult};
-use log::{debug, error, log_enabled, info, Level};
+use sqlx::{MySql, MySqlPool, QueryBuilder};
+use log::{error, info, warn};
+use tokio::sync::broadcast::{self, error::RecvError};
 use serde::{Deserialize, Serialize};
-use chrono::Local;
-use bit_vec::BitVec;
-use crate::models::{game::*, board::*};
-use crate::models::board;
+use crate::models::{game::*, board::*, ship::Ship, event::GameEvent};
 use crate::errors::CustomError;
+use crate::state_machine;
+use crate::ai;
+use crate::controllers::server;
+use crate::pagination::Page;
 
 use crate::AppState;
-use crate::check_access;
+use crate::auth::{self, AuthUser};
+use crate::models::role::Permission;
 
 // The struct used for a new game
 #[derive(Deserialize, Serialize, Debug)]
 pub struct NewGame {
-    boardSize: u8,
+    #[serde(rename = "boardSize")]
+    board_size: u8,
     players: u8,
+    #[serde(default)]
+    difficulty: Difficulty,
+}
+
+// The struct used for adding the server to a game
+#[derive(Deserialize, Serialize, Debug)]
+pub struct AddServer {
+    game_id: u32,
+}
+
+// The query parameters of the WebSocket, for clients that can't send the Authorization header. The token is then part
+// of the URL and can end up in access logs
+#[derive(Deserialize, Debug)]
+pub struct EventsQuery {
+    token: Option<String>,
 }
 
 //handler for creating a new game.
 pub async fn new_game(  State(state): State<AppState>,
                         Extension(pool): Extension<MySqlPool>,
-                        TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
+                        AuthUser(user, permissions): AuthUser,
                         Json(newgame): Json<NewGame>
                         ) -> Result<impl IntoResponse, CustomError> {
 
     info!("new game request");
 
-    //check if user is logged in, bail out if not. Retrieve the user_name from the bearer token
-    let (user_name, _) = check_access(&state, &bearer, false).await?;
+    // No new games while the server is draining
+    if state.shutdown.is_draining() {
+        return Err(CustomError::ShuttingDown);
+    }
+
+    // The game is played with the current rules of the server
+    let rules = server::load_rules(&pool).await?;
 
     // check if board size is correct
-    if newgame.boardSize < 8 || newgame.boardSize > 16 {
-        info!("Illegal boardsize: {:?}", newgame.boardSize);
-        return Err(CustomError::IllegalBoardSize);
+    if newgame.board_size < rules.min_board_size || newgame.board_size > rules.max_board_size {
+        info!("Illegal boardsize: {:?}", newgame.board_size);
+        return Err(CustomError::IllegalBoardSize(rules.min_board_size, rules.max_board_size));
     }
 
     // check if amount of players is correct
-    if newgame.players < 2 || newgame.players > 4 {
+    if newgame.players < rules.min_players || newgame.players > rules.max_players {
         info!("Illegal amount of players: {:?}", newgame.players);
-        return Err(CustomError::InvalidPlayers);
+        return Err(CustomError::InvalidPlayers(rules.min_players, rules.max_players));
     }
 
-    // Check if user is not DDOS-ing the server, max 3 Active games
+    // Check if user is not DDOS-ing the server, the rules limit the amount of Active games
     let sql = "SELECT board.game_id FROM board INNER JOIN game ON board.game_id=game.id WHERE game.status=? AND board.user_name=?";
     let active_games =  match sqlx::query(sql)
         .bind(GameStatus::Active)
-        .bind(&user_name)
+        .bind(&user.name)
         .fetch_all(&pool)
         .await {
             Ok(result) => result,
@@ -61,11 +85,16 @@ pub async fn new_game(  State(state): State<AppState>,
                 return Err(CustomError::BadRequest);              
             }};
 
-    if active_games.len() >= 3 {
-        error!("Active games: {:?} for user {:?}, no more allowed", active_games.len(), user_name);
+    if active_games.len() >= rules.max_active_games as usize && !permissions.has(Permission::UnlimitedGames) {
+        error!("Active games: {:?} for user {:?}, no more allowed", active_games.len(), user.name);
         return Err(CustomError::MaxGames);
     }
 
+    let rules = serde_json::to_vec(&rules).map_err(|err| {
+        error!("Error encoding rules: {:?}", err);
+        CustomError::InternalServerError
+    })?;
+
     // Start transaction
     let mut tx = match pool.begin()
         .await {
@@ -77,13 +106,15 @@ pub async fn new_game(  State(state): State<AppState>,
         };
 
 
-    // Insert game. Most initital values are determined by the DB schema at create time. We want the game_id returned
-    let sql = "INSERT INTO game (board_size, amount_of_players, status) VALUES (?, ?, ?)";
+    // Insert game with a copy of the rules. Most initital values are determined by the DB schema at create time. We want the game_id returned
+    let sql = "INSERT INTO game (board_size, amount_of_players, status, difficulty, rules) VALUES (?, ?, ?, ?, ?)";
 
     let game_id = match sqlx::query(sql)
-        .bind(newgame.boardSize)
+        .bind(newgame.board_size)
         .bind(newgame.players)
-        .bind(GameStatus::Active as u8)
+        .bind(GameStatus::Active)
+        .bind(newgame.difficulty)
+        .bind(rules)
         .execute(&mut tx)
         .await {
             Ok(result) => result.last_insert_id() as u32,
@@ -92,32 +123,16 @@ pub async fn new_game(  State(state): State<AppState>,
                 return Err(CustomError::BadRequest);              
             }};
 
-    let shots_map = BitVec::from_elem((newgame.boardSize as usize) * (newgame.boardSize as usize), false).to_bytes();
-    let player_id: u8 = 1;
-
-    let sql = "INSERT INTO board (game_id, user_name, player_id, status, shots_map) VALUES (?, ?, ?, ?, ?)";
-
-    // Execute the query using the provided data
-    let _ = sqlx::query(sql)
-            .bind(game_id)
-            .bind(user_name)
-            .bind(player_id)
-            .bind(BoardStatus::Placing as u8)
-            .bind(shots_map)
-            .execute(&mut tx)
-            .await
-            .map_err(|err| {
-                error!("Error creating game: {:?}", err);
-                CustomError::BadRequest
-            });
+    // The creator of the game is the first player
+    let game = state_machine::lock_game(&mut tx, game_id).await?;
+    state_machine::add_player(&mut tx, &game, &user.name).await?;
 
     // commit
-    let _ = tx.commit().await
+    tx.commit().await
         .map_err(|err| {
             error!("Error creating game: {:?}", err);
             CustomError::BadRequest
-        }
-    );
+        })?;
 
     // Done
     Ok((StatusCode::OK,"Game started, place your ships"))
@@ -126,40 +141,19 @@ pub async fn new_game(  State(state): State<AppState>,
 
 /////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 //handler for joining an existing game. 
-pub async fn join_game( Path(game_id): Path<String>,
+pub async fn join_game( Path(game_id): Path<u32>,
                         State(state): State<AppState>,
                         Extension(pool): Extension<MySqlPool>,
-                        TypedHeader(bearer): TypedHeader<Authorization<Bearer>>
+                        AuthUser(user, permissions): AuthUser
                         ) -> Result<impl IntoResponse, CustomError> {
     
     info!("Join game request");
 
-    //check if user is logged in, bail out if not. Retrieve the user_name from the bearer token
-    let (user_name, _) = check_access(&state, &bearer, false).await?;
-
-    //Check if game exists and in the right status
-    let sql = "SELECT * FROM game WHERE id = ?";
-    let game: Game = match sqlx::query_as(sql)
-        .bind(&game_id)
-        .fetch_one(&pool)
-        .await {
-            Ok(result) => result,
-            Err(err) => {
-                error!("Error 1 joining game: {:?}", err);
-                return Err(CustomError::BadRequest);              
-            }
-        };
-
-    // Check if the game is active, bail out if not
-    if game.status != GameStatus::Active as u8{
-        return Err(CustomError::GameNotActive);
-    }
-
     // Check if the user is not already in too many other games, if so, bail out
     let sql = "SELECT board.game_id FROM board INNER JOIN game ON board.game_id=game.id WHERE game.status=? AND board.user_name=?";
     let active_games = match sqlx::query(sql)
-        .bind(GameStatus::Active as u8)
-        .bind(&user_name)
+        .bind(GameStatus::Active)
+        .bind(&user.name)
         .fetch_all(&pool)
         .await {
             Ok(result) => result,
@@ -168,52 +162,322 @@ pub async fn join_game( Path(game_id): Path<String>,
                 return Err(CustomError::BadRequest);              
             }};
 
-    if active_games.len() >= 3 {
-        error!("Active games: {:?} for user {:?}, no more allowed", active_games.len(), user_name);
+    // Start transaction
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error joining game: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // Check if game exists and add the player. The state machine checks if the game is active and not full yet
+    let game = state_machine::lock_game(&mut tx, game_id).await?;
+
+    // The limit on active games comes from the rules of the game that is joined
+    if active_games.len() >= game.rules.max_active_games as usize && !permissions.has(Permission::UnlimitedGames) {
+        error!("Active games: {:?} for user {:?}, no more allowed", active_games.len(), user.name);
         return Err(CustomError::MaxGames);
     }
 
-    //Determine the current amount of players for this game
-    let sql = "SELECT * FROM board WHERE game_id=?";
-    let boards = match sqlx::query_as::<_, board::Board>(sql)
-        .bind(&game_id)
+    let player_id = state_machine::add_player(&mut tx, &game, &user.name).await?;
+
+    // commit
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error joining game: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    state.hub.publish(game_id, &GameEvent::PlayerJoined { player_id, user_name: user.name });
+
+    Ok((StatusCode::OK,"Game joined, place your ships"))
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handler for adding the server as a player to a game. The server places a random fleet right away and plays with
+//the difficulty chosen when the game was created. Only players of the game and users with the manage_games
+//permission can add the server.
+pub async fn add_server(State(state): State<AppState>,
+                        Extension(pool): Extension<MySqlPool>,
+                        AuthUser(user, permissions): AuthUser,
+                        Json(add): Json<AddServer>
+                        ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("Add server request");
+
+    // Start transaction
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error adding server: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let game = state_machine::lock_game(&mut tx, add.game_id).await?;
+
+    let boards = state_machine::lock_boards(&mut tx, game.id).await?;
+    if !boards.iter().any(|board| board.user_name == user.name) {
+        info!("User {} is not a player in game {}", user.name, game.id);
+        permissions.require(Permission::ManageGames)?;
+    }
+
+    let (player_id, first) = ai::join(&mut tx, &game).await?;
+
+    // commit
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error adding server: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // The server places its fleet right away and may be the first to shoot
+    state.hub.publish(game.id, &GameEvent::PlayerJoined { player_id, user_name: ai::SERVER_USER.to_string() });
+    state.hub.publish(game.id, &GameEvent::PlacingDone { player_id });
+    if let Some(first) = first {
+        state.hub.publish(game.id, &GameEvent::TurnChanged { player_id: first.player_id });
+    }
+
+    Ok((StatusCode::OK,"Server joined the game"))
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handler for aborting a game. Players can abort their own game, users with the manage_games permission any game.
+pub async fn abort_game(Path(game_id): Path<u32>,
+                        State(state): State<AppState>,
+                        Extension(pool): Extension<MySqlPool>,
+                        AuthUser(user, permissions): AuthUser
+                        ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("Abort game request");
+
+    // Start transaction
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error aborting game: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let game = state_machine::lock_game(&mut tx, game_id).await?;
+
+    if game.status != GameStatus::Active {
+        return Err(CustomError::GameNotActive);
+    }
+
+    // Only players of the game and users with the manage_games permission can abort it
+    let boards = state_machine::lock_boards(&mut tx, game_id).await?;
+    if !boards.iter().any(|board| board.user_name == user.name) {
+        info!("User {} is not a player in game {}", user.name, game_id);
+        permissions.require(Permission::ManageGames)?;
+    }
+
+    state_machine::abort_game(&mut tx, &game).await?;
+
+    // commit
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error aborting game: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    state.hub.publish(game_id, &GameEvent::GameFinished { status: GameStatus::Aborted, winner: None });
+
+    Ok((StatusCode::OK,"Game aborted"))
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handler for the status of a game: the game itself, the public part of every board and whose turn it is.
+//The ships of the requesting user are included, the ships of the other players are never returned.
+pub async fn game_status(Path(game_id): Path<u32>,
+                         Extension(pool): Extension<MySqlPool>,
+                         AuthUser(user, _): AuthUser
+                         ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("Game status request");
+
+    let sql = "SELECT * FROM game WHERE id = ?";
+    let game: Game = sqlx::query_as(sql)
+        .bind(game_id)
+        .fetch_one(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching game {}: {:?}", game_id, err);
+            CustomError::InvalidGame
+        })?;
+
+    let sql = "SELECT player_id, user_name, status, shots_fired, score FROM board WHERE game_id = ? ORDER BY player_id";
+    let boards: Vec<BoardSummary> = sqlx::query_as(sql)
+        .bind(game_id)
         .fetch_all(&pool)
-        .await {
-            Ok(result) => result,
-            Err(err) => {
-                error!("Error 3 joining game: {:?}", err);
-                return Err(CustomError::BadRequest);              
-            }
-        };
+        .await
+        .map_err(|err| {
+            error!("Error fetching boards of game {}: {:?}", game_id, err);
+            CustomError::InternalServerError
+        })?;
+
+    let sql = "SELECT ship.*, ship_class.size FROM ship INNER JOIN ship_class ON ship.class = ship_class.name WHERE ship.game_id = ? AND ship.user_name = ?";
+    let ships: Vec<Ship> = sqlx::query_as(sql)
+        .bind(game_id)
+        .bind(&user.name)
+        .fetch_all(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching ships: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let shooter = boards.iter()
+        .find(|board| board.status == BoardStatus::Shooting)
+        .map(|board| board.player_id);
+
+    Ok((StatusCode::OK, Json(GameOverview { game, boards, shooter, ships })))
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handler for following a game live over a WebSocket. Authenticated with the same Bearer token as every other
+//request, in the Authorization header or for browsers in the token query parameter. Like the status of a game any
+//logged in user can follow it. The events of the game are pushed as json text messages until the game ends, see
+//doc/api.md. The server doesn't expect messages from the client.
+pub async fn game_events(Path(game_id): Path<u32>,
+                         Query(query): Query<EventsQuery>,
+                         State(state): State<AppState>,
+                         Extension(pool): Extension<MySqlPool>,
+                         bearer: Option<TypedHeader<Authorization<Bearer>>>,
+                         ws: WebSocketUpgrade,
+                         ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("Game events request");
+
+    let token = match (&bearer, &query.token) {
+        (Some(TypedHeader(Authorization(bearer))), _) => bearer.token(),
+        (None, Some(token)) => token.as_str(),
+        (None, None) => return Err(CustomError::InvalidToken),
+    };
+    let AuthUser(user, _) = auth::authenticate(&state, &pool, token).await?;
 
-    //check if the user is among the already joined players, if so, bail out
-    for board in &boards {
-        if board.user_name == user_name {
-            error!("User {} is already a player in game {}", user_name, game_id);
-            return Err(CustomError::InvalidGame);
+    // Subscribe before fetching the status, so a game that ends in between is seen as not active instead of leaving
+    // the socket on a channel nobody publishes to. On an error the receiver is dropped, the hub prunes the channel
+    let events = state.hub.subscribe(game_id);
+
+    let sql = "SELECT status FROM game WHERE id = ?";
+    let (status,): (GameStatus,) = sqlx::query_as(sql)
+        .bind(game_id)
+        .fetch_one(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching game {}: {:?}", game_id, err);
+            CustomError::InvalidGame
+        })?;
+
+    if status != GameStatus::Active {
+        return Err(CustomError::GameNotActive);
+    }
+
+    Ok(ws.on_upgrade(move |socket| stream_events(socket, events, game_id, user.name)))
+}
+
+// Forward the events of the game until the game ends or the client goes away
+async fn stream_events(mut socket: WebSocket, mut events: broadcast::Receiver<String>, game_id: u32, user_name: String) {
+
+    info!("{} follows game {}", user_name, game_id);
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let json = match event {
+                    Ok(json) => json,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("{} missed {} events of game {}", user_name, missed, game_id);
+                        match serde_json::to_string(&GameEvent::Lagged { missed }) {
+                            Ok(json) => json,
+                            Err(_) => break,
+                        }
+                    },
+                    Err(RecvError::Closed) => break,
+                };
+                if socket.send(Message::Text(json)).await.is_err() {
+                    break;
+                }
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {},
+            },
         }
     }
 
-    // Prepare new player (board)
-    let shots_map = BitVec::from_elem((game.board_size as usize) * (game.board_size as usize), false).to_bytes();
-    let player_id: u8 = boards.len() as u8 + 1;
-        
-    // Add board to game
-    let sql = "INSERT INTO board (game_id, user_name, player_id, status, shots_map) VALUES (?, ?, ?, ?, ?)";
-
-    // Execute the query using the provided data
-    let _ = sqlx::query(sql)
-            .bind(game_id)
-            .bind(user_name)
-            .bind(player_id)
-            .bind(BoardStatus::Placing as u8)
-            .bind(shots_map)
-            .execute(&pool)
-            .await
-            .map_err(|err| {
-                error!("Error 4 joining game: {:?}", err);
-                CustomError::BadRequest
-            });
+    let _ = socket.close().await;
+    info!("{} stopped following game {}", user_name, game_id);
+}
 
-    Ok((StatusCode::OK,"Game joined, place your ships"))
-}
\ No newline at end of file
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handler for listing games, optionally filtered on player, status, board size and open seats. The result is
+//paginated, the Total-Count header holds the amount of matching games and Next-Page tells if there is more.
+pub async fn list_games(Query(filter): Query<GameFilter>,
+                        Extension(pool): Extension<MySqlPool>,
+                        _user: AuthUser
+                        ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("List games request");
+
+    let page = Page::new(filter.page, filter.per_page);
+
+    // Count all matching games
+    let mut builder = QueryBuilder::new("SELECT COUNT(*) FROM (SELECT game.id FROM game LEFT JOIN board ON board.game_id = game.id");
+    push_game_filter(&mut builder, &filter);
+    builder.push(") AS games");
+    let (total,): (i64,) = builder.build_query_as()
+        .fetch_one(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error counting games: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // Fetch the requested page
+    let mut builder = QueryBuilder::new("SELECT game.*, COUNT(board.user_name) AS players FROM game LEFT JOIN board ON board.game_id = game.id");
+    push_game_filter(&mut builder, &filter);
+    builder.push(" ORDER BY game.id DESC LIMIT ")
+        .push_bind(page.limit)
+        .push(" OFFSET ")
+        .push_bind(page.offset);
+    let games: Vec<GameListing> = builder.build_query_as()
+        .fetch_all(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error listing games: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok((StatusCode::OK, page.headers(total), Json(games)))
+}
+
+// Add the WHERE, GROUP BY and HAVING clauses for the game filter to a query on game LEFT JOIN board
+fn push_game_filter(builder: &mut QueryBuilder<MySql>, filter: &GameFilter) {
+
+    builder.push(" WHERE 1 = 1");
+    if let Some(player) = &filter.player {
+        builder.push(" AND game.id IN (SELECT game_id FROM board WHERE user_name = ")
+            .push_bind(player.clone())
+            .push(")");
+    }
+    if let Some(status) = filter.status {
+        builder.push(" AND game.status = ").push_bind(status);
+    }
+    if let Some(board_size) = filter.board_size {
+        builder.push(" AND game.board_size = ").push_bind(board_size);
+    }
+    if filter.open == Some(true) {
+        builder.push(" AND game.status = ").push_bind(GameStatus::Active);
+    }
+
+    builder.push(" GROUP BY game.id");
+    match filter.open {
+        Some(true) => builder.push(" HAVING COUNT(board.user_name) < game.amount_of_players"),
+        Some(false) => builder.push(" HAVING COUNT(board.user_name) >= game.amount_of_players"),
+        None => builder,
+    };
+}
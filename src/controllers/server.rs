@@ -1,18 +1,22 @@
 use axum::{
     Extension, Json, response::IntoResponse,
-    extract::{Path, TypedHeader, State},
-    headers::{Authorization, authorization::Bearer},
-    http::{StatusCode, header::{HeaderMap, HeaderValue, AUTHORIZATION}},
+    extract::{Path, Query, State},
+    http::StatusCode,
 };
 //use axum_macros::debug_handler;
-use sqlx::MySqlPool;
-use log::{debug, error, log_enabled, info, Level};
+use sqlx::{MySql, MySqlPool, QueryBuilder};
+use log::{error, info};
 use serde::{Deserialize, Serialize};
-use crate::models::server;
+use crate::models::{server::{self, Highscore, HighscoreFilter, ServerStatus}, rules::Rules, ship::ShipClass};
 use crate::errors::CustomError;
+use crate::models::{game::GameStatus, board::BoardStatus, event::GameEvent, job::{Job, JobFilter, JobStatus}};
+use crate::ai::SERVER_USER;
+use crate::{job, state_machine};
+use crate::pagination::Page;
+use chrono::Local;
 
 use crate::AppState;
-use crate::check_access;
+use crate::auth::{Permitted, permit};
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Motd {
@@ -20,8 +24,7 @@ pub struct Motd {
 }
 
 //handler for getting the motd. this request can be done without any auth
-pub async fn get_motd(  State(_state): State<AppState>,
-                        Extension(pool): Extension<MySqlPool>
+pub async fn get_motd(  Extension(pool): Extension<MySqlPool>
                         ) -> Result<impl IntoResponse, CustomError> {
 
     info!("motd request");
@@ -39,18 +42,14 @@ pub async fn get_motd(  State(_state): State<AppState>,
     }
 }
 
-// Handler for setting the motd. First check if the user doing this has an admin Bearer token.
-pub async fn set_motd(  State(state): State<AppState>,
-                        Extension(pool): Extension<MySqlPool>,
-                        TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
+// Handler for setting the motd. Only for users with the set_motd permission.
+pub async fn set_motd(  Extension(pool): Extension<MySqlPool>,
+                        _: Permitted<permit::SetMotd>,
                         Json(motd): Json<Motd>,
                         ) -> Result <(StatusCode,String), CustomError> {
 
     info!("Change MOTD request");
 
-    //check if user is logged in and has the mandatory ADMIN role, bail out if not
-    check_access(&state, &bearer, true).await?;
-
     // Change the MOTD
     let sql = "UPDATE server set motd=? WHERE name='battleship'";
     match sqlx::query(sql)
@@ -63,4 +62,355 @@ pub async fn set_motd(  State(state): State<AppState>,
                 Err(CustomError::BadRequest)
             }
     }
-}
\ No newline at end of file
+}
+
+// Fetch the current rules of the server. When no rules are stored yet the defaults are used, with all ship classes
+// in the ship_class table as the fleet.
+pub async fn load_rules(pool: &MySqlPool) -> Result<Rules, CustomError> {
+
+    let sql = "SELECT rules FROM server";
+    let (rules,): (Option<Vec<u8>>,) = sqlx::query_as(sql)
+        .fetch_one(pool)
+        .await
+        .map_err(|err| {
+            error!("Unexpected error fetching server rules. Error: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    match rules {
+        Some(rules) => Rules::try_from(rules).map_err(|err| {
+            error!("Unexpected error decoding server rules. Error: {:?}", err);
+            CustomError::InternalServerError
+        }),
+        None => Ok(Rules::default_with_fleet(ship_classes(pool).await?)),
+    }
+}
+
+async fn ship_classes(pool: &MySqlPool) -> Result<Vec<ShipClass>, CustomError> {
+
+    let sql = "SELECT * FROM ship_class ORDER BY size DESC, name";
+    sqlx::query_as(sql)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching ship classes: {:?}", err);
+            CustomError::InternalServerError
+        })
+}
+
+//handler for getting the rules used for new games. this request can be done without any auth
+pub async fn get_rules( Extension(pool): Extension<MySqlPool>
+                        ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("rules request");
+
+    Ok((StatusCode::OK, Json(load_rules(&pool).await?)))
+}
+
+// Handler for changing the rules used for new games. Only for users with the manage_rules permission.
+// Games that are already created keep the rules they were created with.
+pub async fn set_rules( Extension(pool): Extension<MySqlPool>,
+                        _: Permitted<permit::ManageRules>,
+                        Json(rules): Json<Rules>,
+                        ) -> Result <(StatusCode,String), CustomError> {
+
+    info!("Change rules request");
+
+    rules.validate().map_err(CustomError::InvalidRules)?;
+
+    // Ships are stored with their class, so every class in the fleet must exist in the ship_class table
+    let classes = ship_classes(&pool).await?;
+    if let Some(class) = rules.fleet.iter().find(|class| !classes.iter().any(|known| known.name == class.name && known.size == class.size)) {
+        return Err(CustomError::InvalidRules(format!("{} of size {} is not a known ship class", class.name, class.size)));
+    }
+
+    let rules = serde_json::to_vec(&rules).map_err(|err| {
+        error!("Error encoding rules: {:?}", err);
+        CustomError::InternalServerError
+    })?;
+
+    let sql = "UPDATE server SET rules=?";
+    match sqlx::query(sql)
+        .bind(rules)
+        .execute(&pool)
+        .await {
+            Ok(_) => Ok((StatusCode::OK, "Rules changed".to_string())),
+            Err(err) => {
+                error!("Error changing rules: {:?}", err);
+                Err(CustomError::BadRequest)
+            }
+    }
+}
+
+//handler for the server status. this request can be done without any auth so monitoring doesn't need an admin account
+pub async fn server_status( State(state): State<AppState>,
+                            Extension(pool): Extension<MySqlPool>
+                            ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("server status request");
+
+    let sql = "SELECT (SELECT COUNT(*) FROM game), (SELECT COUNT(*) FROM user WHERE name <> ?), \
+               (SELECT COUNT(*) FROM game WHERE status = ?), (SELECT motd FROM server LIMIT 1)";
+    let (total_games, total_players, active_games, motd): (i64, i64, i64, Option<String>) = sqlx::query_as(sql)
+        .bind(SERVER_USER)
+        .bind(GameStatus::Active)
+        .fetch_one(&pool)
+        .await
+        .map_err(|err| {
+            error!("Unexpected error fetching server status. Error: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok((StatusCode::OK, Json(ServerStatus {
+        started: state.started,
+        uptime: (Local::now() - state.started).num_seconds(),
+        total_games,
+        total_players,
+        active_games,
+        motd: motd.unwrap_or_default(),
+    })))
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handler for the highscore table of a board size and amount of players. Every game won is an entry, scored on
+//accuracy and speed (see doc/notes.md). Games won by the server itself are left out. This request can be done
+//without any auth. The Total-Count header holds the amount of entries and Next-Page tells if there is more.
+pub async fn highscore( Query(filter): Query<HighscoreFilter>,
+                        Extension(pool): Extension<MySqlPool>
+                        ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("highscore request");
+
+    let page = Page::new(filter.page, filter.per_page);
+
+    let sql = "SELECT COUNT(*) FROM board INNER JOIN game ON board.game_id = game.id \
+               WHERE game.status = ? AND board.status = ? AND game.board_size = ? AND game.amount_of_players = ? \
+               AND board.shots_fired > 0 AND board.user_name <> ?";
+    let (total,): (i64,) = sqlx::query_as(sql)
+        .bind(GameStatus::Finished)
+        .bind(BoardStatus::Won)
+        .bind(filter.board_size)
+        .bind(filter.players)
+        .bind(SERVER_USER)
+        .fetch_one(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error counting highscores: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // accuracy: up to 1000 points per opponent hit with every shot, speed: 500 points halving after 10 minutes
+    let sql = "SELECT board.user_name AS player_id, game.finished AS datetime, game.id AS game_id, \
+               CAST(ROUND(1000 * board.score / board.shots_fired \
+                          + 500 * 600 / (600 + TIMESTAMPDIFF(SECOND, game.started, game.finished))) AS UNSIGNED) AS high_score \
+               FROM board INNER JOIN game ON board.game_id = game.id \
+               WHERE game.status = ? AND board.status = ? AND game.board_size = ? AND game.amount_of_players = ? \
+               AND board.shots_fired > 0 AND board.user_name <> ? \
+               ORDER BY high_score DESC, game.finished ASC LIMIT ? OFFSET ?";
+    let highscores: Vec<Highscore> = sqlx::query_as(sql)
+        .bind(GameStatus::Finished)
+        .bind(BoardStatus::Won)
+        .bind(filter.board_size)
+        .bind(filter.players)
+        .bind(SERVER_USER)
+        .bind(page.limit)
+        .bind(page.offset)
+        .fetch_all(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching highscores: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok((StatusCode::OK, page.headers(total), Json(highscores)))
+}
+
+// Handler for a clean shutdown. Only for users with the manage_server permission. New games are refused
+// from now on, the server stops when all active games are finished.
+pub async fn shutdown(  State(state): State<AppState>,
+                        Permitted(admin, ..): Permitted<permit::ManageServer>,
+                        ) -> Result <(StatusCode,String), CustomError> {
+
+    info!("Shutdown request by {}", admin.name);
+
+    state.shutdown.drain();
+    Ok((StatusCode::ACCEPTED, "Shutting down after all active games are finished".to_string()))
+}
+
+// Handler for a dirty shutdown. Only for users with the manage_server permission. All active games are
+// aborted and the server stops right away.
+pub async fn kill(  State(state): State<AppState>,
+                    Extension(pool): Extension<MySqlPool>,
+                    Permitted(admin, ..): Permitted<permit::ManageServer>,
+                    ) -> Result <(StatusCode,String), CustomError> {
+
+    info!("Kill request by {}", admin.name);
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error killing server: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let aborted = state_machine::abort_all_games(&mut tx).await?;
+
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error killing server: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    for game_id in &aborted {
+        state.hub.publish(*game_id, &GameEvent::GameFinished { status: GameStatus::Aborted, winner: None });
+    }
+
+    state.shutdown.kill();
+    Ok((StatusCode::OK, format!("Server stopped, {} active games aborted", aborted.len())))
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handlers for inspecting the job queue. Only for users with the manage_server permission. Jobs are listed by the
+//time they run, filtered on status, kind and game. The Total-Count header holds the amount of jobs and Next-Page
+//tells if there is more. Dead jobs can be retried, jobs that are not running can be removed.
+pub async fn list_jobs( Query(filter): Query<JobFilter>,
+                        Extension(pool): Extension<MySqlPool>,
+                        _: Permitted<permit::ManageServer>,
+                        ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("List jobs request");
+
+    let page = Page::new(filter.page, filter.per_page);
+
+    let mut builder = QueryBuilder::new("SELECT COUNT(*) FROM job");
+    push_job_filter(&mut builder, &filter);
+    let (total,): (i64,) = builder.build_query_as()
+        .fetch_one(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error counting jobs: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let mut builder = QueryBuilder::new("SELECT * FROM job");
+    push_job_filter(&mut builder, &filter);
+    builder.push(" ORDER BY run_at, id LIMIT ")
+        .push_bind(page.limit)
+        .push(" OFFSET ")
+        .push_bind(page.offset);
+    let jobs: Vec<Job> = builder.build_query_as()
+        .fetch_all(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error listing jobs: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let jobs: Vec<Job> = jobs.into_iter().map(job_view).collect();
+    Ok((StatusCode::OK, page.headers(total), Json(jobs)))
+}
+
+// A job as admins see it, without the addresses and codes in mail payloads
+fn job_view(mut job: Job) -> Job {
+    job.payload = job::redacted(&job.payload);
+    job
+}
+
+fn push_job_filter(builder: &mut QueryBuilder<MySql>, filter: &JobFilter) {
+
+    builder.push(" WHERE 1 = 1");
+    if let Some(status) = filter.status {
+        builder.push(" AND status = ").push_bind(status);
+    }
+    if let Some(kind) = filter.kind {
+        builder.push(" AND kind = ").push_bind(kind);
+    }
+    if let Some(game_id) = filter.game_id {
+        builder.push(" AND game_id = ").push_bind(game_id);
+    }
+}
+
+// Handler for trying a dead job again, right away and with all its attempts
+pub async fn retry_job( Path(id): Path<u64>,
+                        Extension(pool): Extension<MySqlPool>,
+                        Permitted(admin, ..): Permitted<permit::ManageServer>,
+                        ) -> Result <(StatusCode,String), CustomError> {
+
+    info!("Retry job {} request by {}", id, admin.name);
+
+    let sql = "UPDATE job SET status = ?, attempts = 0, run_at = CURRENT_TIMESTAMP WHERE id = ? AND status = ?";
+    let result = sqlx::query(sql)
+        .bind(JobStatus::Pending)
+        .bind(id)
+        .bind(JobStatus::Dead)
+        .execute(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error retrying job {}: {:?}", id, err);
+            CustomError::InternalServerError
+        })?;
+
+    if result.rows_affected() == 0 {
+        return Err(CustomError::JobNotFound);
+    }
+    Ok((StatusCode::OK, "Job queued again".to_string()))
+}
+
+// Handler for removing a job that is pending or dead
+pub async fn delete_job(Path(id): Path<u64>,
+                        Extension(pool): Extension<MySqlPool>,
+                        Permitted(admin, ..): Permitted<permit::ManageServer>,
+                        ) -> Result <(StatusCode,String), CustomError> {
+
+    info!("Delete job {} request by {}", id, admin.name);
+
+    let sql = "DELETE FROM job WHERE id = ? AND status <> ?";
+    let result = sqlx::query(sql)
+        .bind(id)
+        .bind(JobStatus::Running)
+        .execute(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error deleting job {}: {:?}", id, err);
+            CustomError::InternalServerError
+        })?;
+
+    if result.rows_affected() == 0 {
+        return Err(CustomError::JobNotFound);
+    }
+    Ok((StatusCode::OK, "Job removed".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::Task;
+    use crate::mail::template::Template;
+    use crate::models::job::JobKind;
+
+    fn mail_job() -> Job {
+        let task = Task::mail("Alice", "alice@example.org", "en", Template::Verification, &[("code", "4242424242")]);
+        Job {
+            id: 1,
+            kind: JobKind::Mail,
+            game_id: None,
+            payload: serde_json::to_string(&task).unwrap(),
+            status: JobStatus::Pending,
+            attempts: 0,
+            run_at: chrono::Utc::now(),
+            last_error: None,
+            created: chrono::Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn listed_jobs_have_no_secrets() {
+        let response = Json(vec![job_view(mail_job())]).into_response();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(json.contains("verification"));
+        assert!(!json.contains("alice@example.org"));
+        assert!(!json.contains("4242424242"));
+    }
+}
@@ -1,32 +1,31 @@
 use axum::{
-    Extension, Json, response::{IntoResponse,Response},
+    Extension, Json, response::IntoResponse,
     extract::{Path, TypedHeader, State},
-    headers::{Authorization, authorization::{ Basic, Bearer}},
+    headers::{Authorization, authorization::Basic},
     http::StatusCode,
 };
 //use axum_macros::debug_handler;
-use sqlx::MySqlPool;
-use log::{debug, error, log_enabled, info, Level};
-use serde::{Deserialize, Serialize};
+use sqlx::{Executor, MySql, MySqlPool};
+use log::{debug, error, info};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation, TokenData};
-use pwhash::bcrypt;
-use rand;
-use std::env;
-use lettre::{
-    transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
-    Tokio1Executor,
-};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use rand::Rng;
+use sha2::{Digest, Sha256};
 
 use crate::models::user::*;
 use crate::errors::CustomError;
-use crate::Claims;
-use crate::check_access;
+use crate::auth::{AuthUser, Permitted, permit, Claims, load_permissions};
+use crate::models::role::{Permission, Role, UserRoles};
+use crate::models::verification::Purpose;
+use crate::verification;
+use crate::mail::template::{Locale, Template};
+use crate::job::{self, Task};
 use crate::AppState;
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 //handler logging in. We extract Basic authentication to retrieve username and password from db. If password
-//checks out we generate and return the JWT Bearer token which has the expiration and role encoded within
+//checks out we generate and return the short lived JWT Bearer token which has the expiration and role encoded
+//within, together with a refresh token for getting a new Bearer token when it expires
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
 pub async fn login( State(state): State<AppState>,
@@ -53,10 +52,116 @@ pub async fn login( State(state): State<AppState>,
     }
 
     //Check password hash is equal to stored password hash. if not, error out
-    if ! pwhash::bcrypt::verify(basic.password(), &user.password_hash) {
+    if ! state.hasher.verify(basic.password(), &user.password_hash) {
         Err(CustomError::WrongPassword)?;
     }
 
+    // Replace a bcrypt hash or a hash with old parameters now that we have the password
+    if state.hasher.needs_rehash(&user.password_hash) {
+        rehash_password(&state, &pool, &user.name, basic.password()).await;
+    }
+
+    Ok((StatusCode::OK, Json(issue_tokens(&state, &pool, &user).await?)))
+}
+
+// Store a new hash of the password. A failure doesn't stop the login, it is tried again the next time
+async fn rehash_password(state: &AppState, pool: &MySqlPool, user_name: &str, password: &str) {
+
+    let password_hash = match state.hasher.hash(password) {
+        Ok(hash) => hash,
+        Err(_) => return,
+    };
+
+    let sql = "UPDATE user SET password_hash = ? WHERE name = ?";
+    match sqlx::query(sql)
+        .bind(password_hash)
+        .bind(user_name)
+        .execute(pool)
+        .await {
+            Ok(_) => info!("Password hash of {} upgraded", user_name),
+            Err(err) => error!("Error storing rehashed password: {:?}", err),
+        }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handler for refreshing the Bearer token. The refresh token can only be used once, a new refresh token is returned
+//together with the new Bearer token. Refresh tokens of a deactivated user or from before a logout are rejected.
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub async fn refresh_token( State(state): State<AppState>,
+                            Extension(pool): Extension<MySqlPool>,
+                            Json(refresh): Json<RefreshToken>,
+                            ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("refresh token request");
+
+    // Only the hash of the refresh token is stored. Remove it so it can't be used again
+    let token_hash = hash_token(&refresh.refresh_token);
+    let sql = "SELECT user_name, generation FROM refresh_token WHERE token_hash = ? AND expires > CURRENT_TIMESTAMP";
+    let (user_name, generation): (String, u32) = sqlx::query_as(sql)
+        .bind(&token_hash)
+        .fetch_one(&pool)
+        .await
+        .map_err(|err| {
+            error!("Unknown or expired refresh token: {:?}", err);
+            CustomError::InvalidToken
+        })?;
+
+    let sql = "DELETE FROM refresh_token WHERE token_hash = ?";
+    let result = sqlx::query(sql)
+        .bind(&token_hash)
+        .execute(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error removing refresh token: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // The token was used by a concurrent request
+    if result.rows_affected() != 1 {
+        return Err(CustomError::InvalidToken);
+    }
+
+    let sql = "SELECT * FROM user WHERE name = ?";
+    let user: User = sqlx::query_as(sql)
+        .bind(&user_name)
+        .fetch_one(&pool)
+        .await
+        .map_err(|err| {
+            error!("error retrieving user: {:?}", err);
+            CustomError::InvalidToken
+        })?;
+
+    if !user.active {
+        return Err(CustomError::UserDeactivated);
+    }
+
+    if user.token_generation != generation {
+        error!("Refresh token of user {} is revoked", user_name);
+        return Err(CustomError::InvalidToken);
+    }
+
+    Ok((StatusCode::OK, Json(issue_tokens(&state, &pool, &user).await?)))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+//handler for logging out. All Bearer tokens and refresh tokens of the user are revoked, on every device
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub async fn logout(Extension(pool): Extension<MySqlPool>,
+                    AuthUser(user, _): AuthUser,
+                    ) -> Result<impl IntoResponse, CustomError> {
+
+    info!("logout request");
+
+    revoke_tokens(&pool, &user.name).await?;
+
+    Ok((StatusCode::OK, "Logged out".to_string()))
+}
+
+// Generate a Bearer token and a refresh token for the user
+async fn issue_tokens(state: &AppState, pool: &MySqlPool, user: &User) -> Result<AuthResponse, CustomError> {
+
     // Define the registered <Expiration Time> claim (exp) which is the current timestmap plus the defined offset
     let my_exp = Utc::now()
         .checked_add_signed(Duration::seconds(state.token_duration))
@@ -65,57 +170,103 @@ pub async fn login( State(state): State<AppState>,
 
     // Define the Claims struct
     let my_claims = Claims {
-        sub: basic.username().to_string(),          // username
+        sub: user.name.clone(),                     // username
         iat: Utc::now().timestamp() as usize,       // valid from
         exp: my_exp as usize,                       // valid until
         admin: user.admin,                          // user role
+        gen: user.token_generation,                 // revoked when the token generation of the user is raised
+        permissions: load_permissions(pool, user).await?,
     };
 
     // generate the Bearer token
-    match encode(
+    let access_token = match encode(
         &Header::default(),
         &my_claims,
         &EncodingKey::from_secret(state.jwt_secret.as_bytes())
     ) {
         Ok(token) => {
             debug!("Generated token: {token}\n");
-            Ok((StatusCode::OK, Json(AuthResponse{access_token: token, token_type: "bearer".to_string(), expires_in: state.token_duration})))
+            token
         }
         Err(err) => {
             error!("Unexpected error while encoding the bearer token ({:?})", err);
-            Err(CustomError::InternalServerError)
+            return Err(CustomError::InternalServerError);
         }
-    }
+    };
+
+    // generate the refresh token, a random value of which only the hash is stored
+    let refresh_token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    let sql = "INSERT INTO refresh_token (token_hash, user_name, generation, expires) VALUES (?, ?, ?, ?)";
+    sqlx::query(sql)
+        .bind(hash_token(&refresh_token))
+        .bind(&user.name)
+        .bind(user.token_generation)
+        .bind(Utc::now() + Duration::seconds(state.refresh_token_duration))
+        .execute(pool)
+        .await
+        .map_err(|err| {
+            error!("Error storing refresh token: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(AuthResponse{access_token, token_type: "bearer".to_string(), expires_in: state.token_duration, refresh_token})
+}
+
+// Revoke all Bearer tokens and refresh tokens of a user by raising the token generation
+async fn revoke_tokens(pool: &MySqlPool, user_name: &str) -> Result<(), CustomError> {
+
+    let sql = "UPDATE user SET token_generation = token_generation + 1 WHERE name = ?";
+    sqlx::query(sql)
+        .bind(user_name)
+        .execute(pool)
+        .await
+        .map_err(|err| {
+            error!("Error revoking tokens: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let sql = "DELETE FROM refresh_token WHERE user_name = ? OR expires <= CURRENT_TIMESTAMP";
+    sqlx::query(sql)
+        .bind(user_name)
+        .execute(pool)
+        .await
+        .map_err(|err| {
+            error!("Error removing refresh tokens: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+    Ok(())
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
-// Handler for creating a new user as adminsistrator.
-// All fields are determind by the admin without any rules
+// Handler for creating a new user as adminsistrator (manage_users permission).
+// All fields are determind by the admin without any rules, creating an admin needs the manage_admins permission
 // Password is hashed
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
 pub async fn new_user(  State(state): State<AppState>,
                         Extension(pool): Extension<MySqlPool>,
-                        TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
+                        Permitted(_, permissions, ..): Permitted<permit::ManageUsers>,
                         Json(user): Json<NewUser>,
                         ) -> Result <(StatusCode,String), CustomError> {
 
     info!("new user request");
 
-    //check if user is logged in and has the mandatory ADMIN role, bail out if not
-    check_access(&state, &bearer, true).await?;
+    if user.admin {
+        permissions.require(Permission::ManageAdmins)?;
+    }
+
+    // Mail is sent in one of the languages with templates
+    let locale = Locale::parse(&user.locale).ok_or(CustomError::BadRequest)?;
 
     //Create the password hash
-    let password_hash = match bcrypt::hash(user.password) {
-        Ok(hash) => hash,
-        Err(err) => {
-            error!("Unexpected error encrypting password {:?}", err);
-            return Err(CustomError::InternalServerError);
-        }
-    };
+    let password_hash = state.hasher.hash(&user.password)?;
 
     // Create user
-    let sql = "INSERT INTO user (name, password_hash, display_name, email_address, notify) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    let sql = "INSERT INTO user (name, password_hash, display_name, email_address, admin, active, notify, locale) VALUES (?, ?, ?, ?, ?, ?, ?, ?)";
     match sqlx::query(sql)
         .bind(user.name)
         .bind(password_hash)
@@ -124,6 +275,7 @@ pub async fn new_user(  State(state): State<AppState>,
         .bind(user.admin)
         .bind(user.active)
         .bind(user.notify)
+        .bind(locale.tag())
         .execute(&pool)
         .await {
             Ok(_) => Ok((StatusCode::CREATED, "User added".to_string())),
@@ -146,11 +298,21 @@ pub async fn signup(    State(state): State<AppState>,
 
     info!("signup request");
 
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error signing up: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // A signup that was never verified doesn't keep the name or address
+    verification::discard_stale_signups(&mut tx, &user.name, &user.email_address).await?;
+
     // check if user already exists, bail out if that is the case
     let sql = "SELECT * FROM user where name=?";
     if sqlx::query_as::<_,User>(sql)
         .bind(&user.name)
-        .fetch_one(&pool)
+        .fetch_one(&mut tx)
         .await.is_ok() {
                 error!("Trying to signup with a username that already exists");
                 return Err(CustomError::UserExists);
@@ -160,81 +322,68 @@ pub async fn signup(    State(state): State<AppState>,
     let sql = "SELECT * FROM user where email_address=?";
     if sqlx::query_as::<_,User>(sql)
         .bind(&user.email_address)
-        .fetch_one(&pool)
+        .fetch_one(&mut tx)
         .await.is_ok() {
                 error!("Trying to signup with a email-address that already exists");
                 return Err(CustomError::EmailExists);
         }
 
-    // Create a random verification number
-    let verification_number: u32 = rand::random();
+    // The password has to comply with the policy
+    state.policy.check(&user.password, &[&user.name, &user.display_name])?;
 
-    // Create the password hash
-    let password_hash = match bcrypt::hash(user.password) {
-        Ok(hash) => hash,
-        Err(err) => {
-            error!("Unexpected error encrypting password {:?}", err);
-            return Err(CustomError::InternalServerError);
-        }
-    };
+    // Mail is sent in one of the languages with templates
+    let locale = Locale::parse(&user.locale).ok_or(CustomError::BadRequest)?;
 
-    mail_verification_code(&user.display_name, &user.email_address, &verification_number).await?;
+    // Create the password hash
+    let password_hash = state.hasher.hash(&user.password)?;
 
-    // Create user
-    let sql = "INSERT INTO user (name, display_name, email_address, notify, verification, new_password_hash) VALUES (?, ?, ?, ?, ?, ?)";
-    match sqlx::query(sql)
-        .bind(user.name)
-        .bind(user.display_name)
-        .bind(user.email_address)
+    // Create the inactive user, the password is set when the signup is verified
+    let sql = "INSERT INTO user (name, display_name, email_address, notify, locale) VALUES (?, ?, ?, ?, ?)";
+    sqlx::query(sql)
+        .bind(&user.name)
+        .bind(&user.display_name)
+        .bind(&user.email_address)
         .bind(user.notify)
-        .bind(verification_number)
-        .bind(password_hash)
-        .execute(&pool)
-        .await {
-            Ok(_) => Ok((StatusCode::CREATED, "User added, waiting on verification".to_string())),
-            Err(err) => {
-                error!("Error creating user: {:?}", err);
-                Err(CustomError::BadRequest)
-            }
-        }
+        .bind(locale.tag())
+        .execute(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error creating user: {:?}", err);
+            CustomError::BadRequest
+        })?;
 
+    let verification_number = verification::create(&mut tx, &user.name, Purpose::Signup, &password_hash).await?;
+
+    mail_verification_code(&mut tx, &user.display_name, &user.email_address, locale.tag(), verification_number).await?;
+
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error signing up: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok((StatusCode::CREATED, "User added, waiting on verification".to_string()))
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 // Handler for verifying a signup. user must authenticate using basic authentication as there is no bearer token
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-pub async fn signup_verification(   State(state): State<AppState>,
-                                    Extension(pool): Extension<MySqlPool>,
+pub async fn signup_verification(   Extension(pool): Extension<MySqlPool>,
                                     TypedHeader(basic): TypedHeader<Authorization<Basic>>,
                                     Json(verification): Json<Verification>,
                                     ) -> Result <(StatusCode,String), CustomError> {
 
     info!("signup verification request");
 
-    // get user using the id
-    let sql = "SELECT * FROM user where name=?";
-    let user = match sqlx::query_as::<_,User>(sql)
-        .bind(basic.username())
-        .fetch_one(&pool)
-        .await {
-            Ok(user) => user,
-            Err(err) => {
-                error!("Error looking up user for password signup verification: {:?}", err);
-                return Err(CustomError::InternalServerError);
-            }
-    };
-
-    //Check if verification from request is equal to the stored verification 
-    if verification.verification_number != user.verification {
-        error!("Signup verification failed");
-        return Err(CustomError::VerificationFailure);
-    }
+    // Check the code, the pending signup holds the password hash
+    let password_hash = verification::verify(&pool, basic.username(), Purpose::Signup, verification.verification_number).await?;
 
     //Everything checks out, active user
-    let sql = "UPDATE user set password_hash=?, new_password_hash='', verification=0, active=true where name=?";
+    let sql = "UPDATE user set password_hash=?, active=true where name=?";
     match sqlx::query(sql)
-        .bind(user.new_password_hash)
+        .bind(password_hash)
         .bind(basic.username())
         .execute(&pool)
         .await {
@@ -249,47 +398,42 @@ pub async fn signup_verification(   State(state): State<AppState>,
 
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
-// Handler for looking up a user.
+// Handler for looking up a user. Only for users with the manage_users permission
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-pub async fn get_user(  Path(id): Path<String>, State(state): State<AppState>,
+pub async fn get_user(  Path(id): Path<String>,
                         Extension(pool): Extension<MySqlPool>,
-                        TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
+                        _: Permitted<permit::ManageUsers>,
                         ) -> Result <impl IntoResponse, CustomError> {
 
     info!("get user request");
 
-    //check if user is logged in and has the mandatory ADMIN role, bail out if not
-    check_access(&state, &bearer, true).await?;
-
     // get user using the id
     let sql = "SELECT * FROM user where name=?";
     match sqlx::query_as::<_,User>(sql)
         .bind(id)
         .fetch_one(&pool)
         .await {
-            Ok(user) => Ok((StatusCode::OK,Json(user))),
+            Ok(user) => Ok((StatusCode::OK, Json(AdminUserView::from(user)))),
             Err(_) => Err(CustomError::UserNotFound)
     }
 }
+
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
-// Handler for updating a user.
+// Handler for updating a user. Updating an admin or making one needs the manage_admins permission
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-pub async fn update_user(   Path(id): Path<String>, State(state): State<AppState>,
+pub async fn update_user(   Path(id): Path<String>,
                             Extension(pool): Extension<MySqlPool>,
-                            TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
-                            Json(updateUser): Json<UpdateUser>,
+                            Permitted(_, permissions, ..): Permitted<permit::ManageUsers>,
+                            Json(update): Json<UpdateUser>,
                             ) -> Result <impl IntoResponse, CustomError> {
 
     info!("Update user request");
 
-    //check if user is logged in and has the mandatory ADMIN role, bail out if not
-    check_access(&state, &bearer, true).await?;
-
     // Fetch the user using the username from the basic authentication header
     let sql = "SELECT * FROM user WHERE name = ?";
-    let _: User = sqlx::query_as(sql)
+    let user: User = sqlx::query_as(sql)
         .bind(&id)
         .fetch_one(&pool)
         .await
@@ -297,48 +441,321 @@ pub async fn update_user(   Path(id): Path<String>, State(state): State<AppState
             CustomError::UserNotFound
         })?;
 
+    if user.admin || update.admin {
+        permissions.require(Permission::ManageAdmins)?;
+    }
+
     // Update user
     let sql = "UPDATE user set display_name=?, email_address=?, admin=?, active=?, notify=? WHERE name = ?";
     match sqlx::query(sql)
-        .bind(updateUser.display_name)
-        .bind(updateUser.email_address)
-        .bind(updateUser.admin)
-        .bind(updateUser.active)
-        .bind(updateUser.notify)
+        .bind(update.display_name)
+        .bind(update.email_address)
+        .bind(update.admin)
+        .bind(update.active)
+        .bind(update.notify)
         .bind(&id)
         .execute(&pool)
         .await {
-            Ok(_) => Ok((StatusCode::OK, "User updated".to_string())),
+            Ok(_) => (),
             Err(err) => {
                 error!("Error updating user: {:?}", err);
-                Err(CustomError::BadRequest)
+                return Err(CustomError::BadRequest);
             }
     }
 
+    // A deactivated user or a demoted admin loses access right away
+    if (user.active && !update.active) || (user.admin && !update.admin) {
+        revoke_tokens(&pool, &id).await?;
+    }
+
+    if user.active && !update.active {
+        mail_user(&pool, &user, Template::AccountDeactivated).await;
+    }
+
+    Ok((StatusCode::OK, "User updated".to_string()))
+
     //TODO if user.name is changed (not equal to id) then also update user.name in table <boards>
 
 }
 
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Handler for showing the profile of the logged in user
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub async fn get_me(    AuthUser(user, _): AuthUser,
+                        ) -> Result <impl IntoResponse, CustomError> {
+
+    info!("get profile request");
+
+    Ok((StatusCode::OK, Json(Profile::from(user))))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Handler for updating the profile of the logged in user. Only the fields in the request are changed. A new email
+// address is changed after it is verified, the verification code is mailed to the new address.
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub async fn update_me( Extension(pool): Extension<MySqlPool>,
+                        AuthUser(user, _): AuthUser,
+                        Json(update): Json<UpdateProfile>,
+                        ) -> Result <impl IntoResponse, CustomError> {
+
+    info!("Update profile request");
+
+    if let Some(display_name) = &update.display_name {
+        if display_name.trim().is_empty() || display_name.chars().count() > 32 {
+            error!("Invalid display name in profile update");
+            return Err(CustomError::BadRequest);
+        }
+    }
+
+    // Mail is sent in one of the languages with templates
+    let locale = match &update.locale {
+        Some(tag) => Some(Locale::parse(tag).ok_or(CustomError::BadRequest)?.tag()),
+        None => None,
+    };
+
+    // Only a different address needs to be verified
+    let new_email = match update.email_address {
+        Some(email_address) if email_address != user.email_address => {
+            if email_address.parse::<lettre::Address>().is_err() || email_address.len() > 64 {
+                error!("Invalid email address in profile update");
+                return Err(CustomError::BadRequest);
+            }
+            if email_in_use(&pool, &email_address).await? {
+                error!("Trying to change to an email address that already exists");
+                return Err(CustomError::EmailExists);
+            }
+            Some(email_address)
+        },
+        _ => None,
+    };
+
+    // The profile and a pending address change are stored together, or not at all
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error updating profile: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let sql = "UPDATE user SET display_name = COALESCE(?, display_name), notify = COALESCE(?, notify), locale = COALESCE(?, locale) \
+               WHERE name = ?";
+    sqlx::query(sql)
+        .bind(&update.display_name)
+        .bind(update.notify)
+        .bind(locale)
+        .bind(&user.name)
+        .execute(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error updating profile: {:?}", err);
+            CustomError::BadRequest
+        })?;
+
+    if let Some(email_address) = &new_email {
+        let verification_number = verification::create(&mut tx, &user.name, Purpose::EmailChange, email_address).await?;
+        let display_name = update.display_name.as_deref().unwrap_or(&user.display_name);
+        let locale = locale.unwrap_or(&user.locale);
+        mail_verification_code(&mut tx, display_name, email_address, locale, verification_number).await?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error updating profile: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    match new_email {
+        Some(_) => Ok((StatusCode::OK, "Profile updated, please verify your new email address".to_string())),
+        None => Ok((StatusCode::OK, "Profile updated".to_string())),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Handler for verifying a new email address of the logged in user
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub async fn email_verification(Extension(pool): Extension<MySqlPool>,
+                                AuthUser(user, _): AuthUser,
+                                Json(verification): Json<Verification>,
+                                ) -> Result <(StatusCode,String), CustomError> {
+
+    info!("email change verification request");
+
+    // Check the code, the pending change holds the new address
+    let email_address = verification::verify(&pool, &user.name, Purpose::EmailChange, verification.verification_number).await?;
+
+    // Someone could have taken the address in the meantime
+    if email_in_use(&pool, &email_address).await? {
+        error!("Email address was taken before the change was verified");
+        return Err(CustomError::EmailExists);
+    }
+
+    let sql = "UPDATE user SET email_address = ? WHERE name = ?";
+    match sqlx::query(sql)
+        .bind(email_address)
+        .bind(&user.name)
+        .execute(&pool)
+        .await {
+            Ok(_) => Ok((StatusCode::OK, "Email address verified and changed".to_string())),
+            Err(err) => {
+                error!("Error changing email address: {:?}", err);
+                Err(CustomError::BadRequest)
+            }
+        }
+}
+
+// Check if an account uses the email address
+async fn email_in_use(pool: &MySqlPool, email_address: &str) -> Result<bool, CustomError> {
+
+    let sql = "SELECT COUNT(*) FROM user WHERE email_address = ?";
+    let count: i64 = sqlx::query_scalar(sql)
+        .bind(email_address)
+        .fetch_one(pool)
+        .await
+        .map_err(|err| {
+            error!("Error looking up email address: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(count > 0)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Handler for listing the roles and their permissions.
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub async fn list_roles(    Extension(pool): Extension<MySqlPool>,
+                            _user: AuthUser,
+                            ) -> Result <impl IntoResponse, CustomError> {
+
+    info!("List roles request");
+
+    let sql = "SELECT role.name, role_permission.permission FROM role \
+               LEFT JOIN role_permission ON role.name = role_permission.role ORDER BY role.name";
+    let rows: Vec<(String, Option<Permission>)> = sqlx::query_as(sql)
+        .fetch_all(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching roles: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let mut roles: Vec<Role> = Vec::new();
+    for (name, permission) in rows {
+        if roles.last().map(|role| role.name != name).unwrap_or(true) {
+            roles.push(Role { name, permissions: Vec::new() });
+        }
+        if let (Some(role), Some(permission)) = (roles.last_mut(), permission) {
+            role.permissions.push(permission);
+        }
+    }
+
+    Ok((StatusCode::OK, Json(roles)))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Handler for setting the roles of a user. The roles replace the current roles, the tokens of the user are revoked
+// so the new permissions are used from the next login. The roles of an admin need the manage_admins permission.
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub async fn set_user_roles(Path(id): Path<String>,
+                            Extension(pool): Extension<MySqlPool>,
+                            Permitted(_, permissions, ..): Permitted<permit::ManageUsers>,
+                            Json(update): Json<UserRoles>,
+                            ) -> Result <impl IntoResponse, CustomError> {
+
+    info!("Set user roles request");
+
+    let sql = "SELECT name FROM role";
+    let roles: Vec<(String,)> = sqlx::query_as(sql)
+        .fetch_all(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching roles: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    if let Some(unknown) = update.roles.iter().find(|role| !roles.iter().any(|(name,)| name == *role)) {
+        return Err(CustomError::UnknownRole(unknown.clone()));
+    }
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error setting roles: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let sql = "SELECT * FROM user WHERE name = ? FOR UPDATE";
+    let user: User = sqlx::query_as(sql)
+        .bind(&id)
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|_| {
+            CustomError::UserNotFound
+        })?;
+
+    if user.admin {
+        permissions.require(Permission::ManageAdmins)?;
+    }
+
+    let sql = "DELETE FROM user_role WHERE user_name = ?";
+    sqlx::query(sql)
+        .bind(&id)
+        .execute(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error removing roles: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    for role in &update.roles {
+        let sql = "INSERT IGNORE INTO user_role (user_name, role) VALUES (?, ?)";
+        sqlx::query(sql)
+            .bind(&id)
+            .bind(role)
+            .execute(&mut tx)
+            .await
+            .map_err(|err| {
+                error!("Error adding role: {:?}", err);
+                CustomError::InternalServerError
+            })?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error setting roles: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    revoke_tokens(&pool, &id).await?;
+
+    Ok((StatusCode::OK, "Roles updated".to_string()))
+}
+
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 // Handler for changing a password.
-// When logged in user is admin it is possible to change password for another user
-// When logged in user is not admin it must be the same user as in the bearer token
+// When logged in user has the manage_users permission it is possible to change password for another user,
+// for an admin the manage_admins permission is needed too
+// Otherwise it must be the same user as in the bearer token
+// Once the password is changed all tokens of the user are revoked
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
 pub async fn change_password(   Path(id): Path<String>, State(state): State<AppState>,
                                 Extension(pool): Extension<MySqlPool>,
-                                TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
+                                AuthUser(caller, permissions): AuthUser,
                                 Json(passwords): Json<ChangePassword>,
                                 ) -> Result <impl IntoResponse, CustomError> {
 
     info!("Change password request");
 
-    //check if user is logged in, bail out if not, retrieve the user and admin status from the token
-    let (token_user, token_admin) = check_access(&state, &bearer, false).await?;
-
-    // You can only change someone else password if you are an admin. If not, bail out
-    if !token_admin && token_user != id {
-        return Err(CustomError::NotAdmin)
+    // You can only change someone else password with the manage_users permission. If not, bail out
+    if caller.name != id {
+        permissions.require(Permission::ManageUsers)?;
     }
 
     // get user using the id
@@ -353,26 +770,27 @@ pub async fn change_password(   Path(id): Path<String>, State(state): State<AppS
                 return Err(CustomError::InternalServerError);
             }
     };
+
+    if caller.name != id && user.admin {
+        permissions.require(Permission::ManageAdmins)?;
+    }
     
-    if ! bcrypt::verify(passwords.old_password, &user.password_hash) {
+    if ! state.hasher.verify(&passwords.old_password, &user.password_hash) {
     // check if old password is equal to the stored password hash. If not, bail out
         error!("Password verification failed while changing password");
         return Err(CustomError::WrongPassword);
     }
 
+    // The new password has to comply with the policy
+    state.policy.check(&passwords.new_password, &[&user.name, &user.display_name])?;
+
     //Create the password hash of the new password
-    let new_password_hash = match bcrypt::hash(passwords.new_password) {
-        Ok(hash) => hash,
-        Err(err) => {
-            error!("Unexpected error encrypting password {:?}", err);
-            return Err(CustomError::InternalServerError);
-        }
-    };
+    let new_password_hash = state.hasher.hash(&passwords.new_password)?;
 
     
     //ADMIN: Change new password_hash
-    //USER: Store new password_hash in new_password_hash and verification_number in verification
-    if token_admin {
+    //USER: Store new password_hash with a verification code that has to be confirmed
+    if permissions.has(Permission::ManageUsers) {
 
         let sql = "UPDATE user set password_hash=? WHERE name = ?";
         match sqlx::query(sql)
@@ -380,7 +798,11 @@ pub async fn change_password(   Path(id): Path<String>, State(state): State<AppS
             .bind(id)
             .execute(&pool)
             .await {
-                Ok(_) => Ok((StatusCode::OK, "Password changed".to_string())),
+                Ok(_) => {
+                    revoke_tokens(&pool, &user.name).await?;
+                    mail_user(&pool, &user, Template::PasswordChanged).await;
+                    Ok((StatusCode::OK, "Password changed".to_string()))
+                },
                 Err(err) => {
                     error!("Error 1 changing password: {:?}", err);
                     Err(CustomError::BadRequest)
@@ -388,117 +810,293 @@ pub async fn change_password(   Path(id): Path<String>, State(state): State<AppS
         }
     } else {
 
-        let verification_number: u32 = rand::random();
+        let verification_number = verification::create(&pool, &id, Purpose::PasswordChange, &new_password_hash).await?;
 
-        mail_verification_code(&user.display_name, &user.email_address, &verification_number).await?;
+        mail_verification_code(&pool, &user.display_name, &user.email_address, &user.locale, verification_number).await?;
 
-        let sql = "UPDATE user set verification=?, new_password_hash=? WHERE name = ?";
-        match sqlx::query(sql)
-            .bind(verification_number)
-            .bind(new_password_hash)
-            .bind(id)
-            .execute(&pool)
-            .await {
-                Ok(_) => Ok((StatusCode::OK, "Please verify your password change request".to_string())),
-                Err(err) => {
-                    error!("Error 2 changing password: {:?}", err);
-                    Err(CustomError::BadRequest)
-                }
-        }
+        Ok((StatusCode::OK, "Please verify your password change request".to_string()))
     }
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
-// Handler for verifying a password change.
+// Handler for verifying a password change. All tokens of the user are revoked, including the one used here.
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-pub async fn password_verification( State(state): State<AppState>,
-                                    Extension(pool): Extension<MySqlPool>,
-                                    TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
+pub async fn password_verification( Extension(pool): Extension<MySqlPool>,
+                                    AuthUser(user, _): AuthUser,
                                     Json(verification): Json<Verification>,
                                     ) -> Result <(StatusCode,String), CustomError> {
 
     info!("password change verification request");
 
-    //check if user is logged in and has the mandatory ADMIN role, bail out if not
-    let (user_name, _) = check_access(&state, &bearer, false).await?;
+    // Check the code, the pending change holds the new password hash
+    let password_hash = verification::verify(&pool, &user.name, Purpose::PasswordChange, verification.verification_number).await?;
 
-    // get user using the id
-    let sql = "SELECT * FROM user where name=?";
+    //Everything checks out, change the password
+    let sql = "UPDATE user set password_hash=? WHERE name = ?";
+    match sqlx::query(sql)
+        .bind(password_hash)
+        .bind(&user.name)
+        .execute(&pool)
+        .await {
+            Ok(_) => {
+                revoke_tokens(&pool, &user.name).await?;
+                mail_user(&pool, &user, Template::PasswordChanged).await;
+                Ok((StatusCode::OK, "Password change request verified. Password changed".to_string()))
+            },
+            Err(err) => {
+                error!("Error updating user: {:?}", err);
+                Err(CustomError::BadRequest)
+            }
+        }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Handler for a forgotten password. The user is looked up by name or email address. The new password is stored
+// with a password reset verification code that expires, the code is mailed. The response is the same whether the
+// account exists or not. The response time isn't, only an existing account stores a code and queues a mail.
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub async fn forgot_password(   State(state): State<AppState>,
+                                Extension(pool): Extension<MySqlPool>,
+                                Json(forgot): Json<ForgotPassword>,
+                                ) -> Result <(StatusCode,String), CustomError> {
+
+    info!("forgot password request");
+
+    let response = Ok((StatusCode::OK, "When the account exists a verification code has been mailed".to_string()));
+
+    // The policy is checked before the lookup so the answer doesn't depend on the account, the only name known
+    // at this point is what was entered
+    state.policy.check(&forgot.new_password, &[&forgot.user])?;
+
+    // Hash first, the slowest step is done for unknown accounts too
+    let new_password_hash = state.hasher.hash(&forgot.new_password)?;
+
+    let sql = "SELECT * FROM user WHERE name = ? OR email_address = ?";
     let user = match sqlx::query_as::<_,User>(sql)
-        .bind(user_name)
-        .fetch_one(&pool)
+        .bind(&forgot.user)
+        .bind(&forgot.user)
+        .fetch_optional(&pool)
         .await {
-            Ok(user) => user,
+            Ok(Some(user)) if user.active => user,
+            Ok(_) => {
+                info!("Password reset requested for an unknown or inactive account");
+                return response;
+            }
             Err(err) => {
-                error!("Error looking up user for password signup verification: {:?}", err);
+                error!("Error looking up user for password reset: {:?}", err);
                 return Err(CustomError::InternalServerError);
             }
     };
 
-    //Check if verification from request is equal to the stored verification 
-    if verification.verification_number != user.verification {
-        error!("Signup verification failed");
+    let verification_number = verification::create(&pool, &user.name, Purpose::PasswordReset, &new_password_hash).await?;
+
+    if let Err(err) = mail_verification_code(&pool, &user.display_name, &user.email_address, &user.locale, verification_number).await {
+        error!("Error mailing password reset code: {:?}", err);
+    }
+
+    response
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Handler for resetting a password with the mailed verification code. The code can be used once, after that,
+// after it expired or after too many wrong attempts the reset has to be requested again. All tokens of the user are revoked.
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub async fn reset_password(    Extension(pool): Extension<MySqlPool>,
+                                Json(reset): Json<ResetPassword>,
+                                ) -> Result <(StatusCode,String), CustomError> {
+
+    info!("reset password request");
+
+    let sql = "SELECT * FROM user WHERE name = ? OR email_address = ?";
+    let user = sqlx::query_as::<_,User>(sql)
+        .bind(&reset.user)
+        .bind(&reset.user)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|err| {
+            error!("Error looking up user for password reset: {:?}", err);
+            CustomError::InternalServerError
+        })?
+        .ok_or(CustomError::VerificationFailure)?;
+
+    // Unknown and inactive accounts get the same error as a wrong code
+    if !user.active {
+        error!("Password reset verification failed");
         return Err(CustomError::VerificationFailure);
     }
 
-    //Everything checks out, active user
-    let sql = "UPDATE user set password_hash=new_password_hash, new_password_hash='', verification=0, active=true";
-    match sqlx::query(sql)
+    // Check the code, the pending reset holds the new password hash
+    let password_hash = verification::verify(&pool, &user.name, Purpose::PasswordReset, reset.verification_number).await?;
+
+    let sql = "UPDATE user set password_hash=? WHERE name = ?";
+    sqlx::query(sql)
+        .bind(password_hash)
+        .bind(&user.name)
         .execute(&pool)
-        .await {
-            Ok(_) => Ok((StatusCode::OK, "Password change request verified. Password changed".to_string())),
-            Err(err) => {
-                error!("Error updating user: {:?}", err);
-                return Err(CustomError::BadRequest);
-            }
-        }   
+        .await
+        .map_err(|err| {
+            error!("Error resetting password: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    revoke_tokens(&pool, &user.name).await?;
+
+    mail_user(&pool, &user, Template::PasswordChanged).await;
+
+    Ok((StatusCode::OK, "Password reset, please login with your new password".to_string()))
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
-// Send verification mail message 
+// Queue the mail messages, the job workers send them
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-async fn mail_verification_code(display_name: &String, email_address: &String, verification_number: &u32) -> Result<(),CustomError> {
+async fn mail_verification_code<'e, E>(executor: E, display_name: &str, email_address: &str, locale: &str,
+                                verification_number: u32) -> Result<(),CustomError>
+    where E: Executor<'e, Database = MySql> {
 
-    // Get the mail settings from environment variables
-    let email_from = env::var("EMAIL_FROM").expect("$EMAIL_FROM is not set");
-    let email_reply_to_name = env::var("EMAIL_REPLY_TO_NAME").expect("$EMAIL_REPLY_TO_NAME is not set");
-    let email_reply_to_address = env::var("EMAIL_REPLY_TO_ADDRESS").expect("$EMAIL_REPLY_TO_ADDRESS is not set");
-    let smtp_username = env::var("SMTP_USERNAME").expect("$SMTP_USERNAME is not set");
-    let smtp_password = env::var("SMTP_PASSWORD").expect("$SMTP_PASSWORD is not set");
-    let smtp_host = env::var("SMTP_HOST").expect("$SMTP_HOST is not set");
+    let code = verification_number.to_string();
+    let task = Task::mail(display_name, email_address, locale, Template::Verification, &[("code", &code)]);
+    job::enqueue(executor, &task, Utc::now()).await
+}
 
-    // Construct the mail message
-    let email = match Message::builder()
-        .from(email_from.parse().unwrap())
-        .reply_to(format!("{} <{}>", email_reply_to_name, email_reply_to_address).parse().unwrap())
-        .to(format!("{} <{}>", display_name, email_address).parse().unwrap())
-        .subject("Here is your verification code for the requested action on the See Battle server")
-        .body(String::from(format!("Verification number: {}", verification_number))) {
-            Ok(message) => message,
-            Err(err) => {
-                error!("Error building mail message: {:?}", err);
-                return Err(CustomError::InternalServerError);
-            }
-        };
+// Queue a mail for the user, a failure is only logged
+async fn mail_user(pool: &MySqlPool, user: &User, template: Template) {
 
+    let task = Task::mail(&user.display_name, &user.email_address, &user.locale, template, &[]);
+    if let Err(err) = job::enqueue(pool, &task, Utc::now()).await {
+        error!("Error mailing {:?} to {}: {:?}", template, user.email_address, err);
+    }
+}
 
-    // Open a remote connection using STARTTLS
-    let creds = Credentials::new(smtp_username, smtp_password);
-    let mailer: AsyncSmtpTransport<Tokio1Executor> =
-    AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp_host)
-        .unwrap()
-        .credentials(creds)
-        .build();
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// No user response may contain the password hash, verification codes or the token generation. The responses are
+// built from a user with a recognizable hash and checked for forbidden fields and for the hash itself.
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-    // Send the email
-    match mailer.send(email).await {
-        Ok(_) => Ok(()),
-        Err(err) => {
-            error!("Error while sending mail: {:?}", err);
-            Err(CustomError::InternalServerError)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::Response;
+    use serde_json::Value;
+    use crate::auth::Permissions;
+    use crate::{shutdown, rate_limit, password, mail};
+
+    const HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$c2VjcmV0c2FsdA$secret-hash-that-must-not-leak";
+    const FORBIDDEN: [&str; 7] = ["password_hash", "new_password_hash", "verification", "verification_number",
+                                  "code", "payload", "token_generation"];
+
+    fn user() -> User {
+        User {
+            name: "alice".to_string(),
+            password_hash: HASH.to_string(),
+            display_name: "Alice".to_string(),
+            email_address: "alice@example.org".to_string(),
+            admin: false,
+            active: true,
+            notify: true,
+            token_generation: 7,
+            locale: "nl".to_string(),
         }
     }
 
+    fn state() -> AppState {
+        AppState {
+            jwt_secret: "secret".to_string(),
+            token_duration: 60,
+            refresh_token_duration: 60,
+            started: chrono::Local::now(),
+            shutdown: shutdown::Shutdown::new(),
+            limiter: std::sync::Arc::new(rate_limit::RateLimiter::new()),
+            hasher: password::Hasher::from_env(),
+            policy: password::Policy::from_env(),
+            mailer: std::sync::Arc::new(mail::MemoryMailer::new(mail::Sender {
+                from: "Sea Battle <server@example.org>".parse().unwrap(),
+                reply_to: "Admin <admin@example.org>".parse().unwrap(),
+            })),
+            hub: crate::hub::Hub::new(),
+        }
+    }
+
+    async fn body(response: Response) -> Value {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    // Fails on a forbidden field or the hash anywhere in the json
+    fn assert_no_secrets(value: &Value) {
+        match value {
+            Value::Object(fields) => {
+                for (key, field) in fields {
+                    assert!(!FORBIDDEN.contains(&key.as_str()), "response contains {}", key);
+                    assert_no_secrets(field);
+                }
+            },
+            Value::Array(values) => values.iter().for_each(assert_no_secrets),
+            Value::String(text) => assert!(!text.contains("secret-hash"), "response contains the password hash"),
+            _ => {},
+        }
+    }
+
+    fn keys(value: &Value) -> Vec<&str> {
+        let mut keys: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test]
+    async fn get_me_has_no_secrets() {
+        let response = get_me(AuthUser(user(), Permissions(vec![]))).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let json = body(response).await;
+        assert_no_secrets(&json);
+        assert_eq!(keys(&json), ["admin", "display_name", "email_address", "locale", "name", "notify"]);
+    }
+
+    #[tokio::test]
+    async fn admin_user_view_has_no_secrets() {
+        let json = body(Json(AdminUserView::from(user())).into_response()).await;
+        assert_no_secrets(&json);
+        assert_eq!(keys(&json), ["active", "admin", "display_name", "email_address", "locale", "name", "notify"]);
+    }
+
+    // A pool that never connects, every query fails
+    fn pool() -> MySqlPool {
+        sqlx::mysql::MySqlPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_millis(100))
+            .connect_lazy("mysql://nobody@127.0.0.1:1/seabattle")
+            .unwrap()
+    }
+
+    fn manager() -> Permitted<permit::ManageUsers> {
+        Permitted(user(), Permissions(Permission::ALL.to_vec()), std::marker::PhantomData)
+    }
+
+    #[tokio::test]
+    async fn failing_handlers_have_no_secrets() {
+        let response = get_user(Path("alice".to_string()), Extension(pool()), manager()).await.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_no_secrets(&body(response).await);
+
+        let update = UpdateProfile { display_name: None, email_address: Some("new@example.org".to_string()), notify: None, locale: None };
+        let response = update_me(Extension(pool()), AuthUser(user(), Permissions(vec![])), Json(update)).await.into_response();
+        assert!(response.status().is_server_error());
+        assert_no_secrets(&body(response).await);
+    }
+
+    #[tokio::test]
+    async fn error_responses_have_no_secrets() {
+        let errors = [
+            CustomError::WrongPassword,
+            CustomError::VerificationFailure,
+            CustomError::UserNotFound,
+            CustomError::TooManyAttempts(60),
+            state().policy.check("alice", &["alice", "Alice"]).unwrap_err(),
+        ];
+        for error in errors {
+            assert_no_secrets(&body(error.into_response()).await);
+        }
+    }
 }
@@ -1,7 +1,10 @@
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use axum::{http::{header, StatusCode}, response::IntoResponse, Json};
 use serde_json::json;
+use crate::models::role::Permission;
+use crate::password::PolicyViolation;
 
 // Custom Errors used in handlers
+#[derive(Debug)]
 pub enum CustomError {
     BadRequest,
     UserNotFound,
@@ -9,36 +12,78 @@ pub enum CustomError {
     EmailExists,
     UserDeactivated,
     WrongPassword,
-    NotAdmin,
+    Forbidden(Permission),
     InternalServerError,
     InvalidToken,
     MaxGames,
-    IllegalBoardSize,
-    InvalidPlayers,
+    IllegalBoardSize(u8, u8),
+    InvalidPlayers(u8, u8),
     GameNotActive,
     InvalidGame,
     VerificationFailure,
+    InvalidPlayer,
+    NotPlacing,
+    UnknownShipClass,
+    ShipPlaced,
+    ShipOutOfBounds,
+    ShipOverlap,
+    NotYourTurn,
+    ShotOutOfBounds,
+    AlreadyShot,
+    GameFull,
+    InvalidTransition,
+    InvalidRules(String),
+    ShipTouching,
+    ShuttingDown,
+    TooManyAttempts(u64),
+    WeakPassword(Vec<PolicyViolation>),
+    UnknownRole(String),
+    JobNotFound,
 }
 
 //implementation of custom errors that are used in handlers
 impl IntoResponse for CustomError {
     fn into_response(self) -> axum::response::Response {
         let (status, error_message) = match self {
-            Self::InternalServerError => (StatusCode::INTERNAL_SERVER_ERROR,"Internal Server Error"),
-            Self::BadRequest => (StatusCode::BAD_REQUEST, "Bad Request"),
-            Self::UserNotFound => (StatusCode::NOT_FOUND, "User not Found"),
-            Self::UserExists => (StatusCode::BAD_REQUEST, "User already exists"),
-            Self::EmailExists => (StatusCode::BAD_REQUEST, "You already have an account"),
-            Self::UserDeactivated => (StatusCode::BAD_REQUEST, "User deactivated"),
-            Self::WrongPassword => (StatusCode::UNAUTHORIZED, "Wrong Password"),
-            Self::NotAdmin => (StatusCode::UNAUTHORIZED, "You need to be an administrator for this request"),
-            Self::InvalidToken => (StatusCode::UNAUTHORIZED, "Token is not valid"),
-            Self::MaxGames => (StatusCode::TOO_MANY_REQUESTS, "You already have the maximum amount of active games on this server"),
-            Self::IllegalBoardSize => (StatusCode::BAD_REQUEST, "Board Size must be between 8 and 16"),
-            Self::InvalidPlayers => (StatusCode::BAD_REQUEST, "Number of players must be between 2 and 4"),
-            Self::GameNotActive => (StatusCode::BAD_REQUEST, "Game is not active"),
-            Self::InvalidGame => (StatusCode::BAD_REQUEST, "Invalid Game"),
-            Self::VerificationFailure => (StatusCode::BAD_REQUEST, "Verification failed"),
+            Self::InternalServerError => (StatusCode::INTERNAL_SERVER_ERROR,"Internal Server Error".to_string()),
+            Self::BadRequest => (StatusCode::BAD_REQUEST, "Bad Request".to_string()),
+            Self::UserNotFound => (StatusCode::NOT_FOUND, "User not Found".to_string()),
+            Self::UserExists => (StatusCode::BAD_REQUEST, "User already exists".to_string()),
+            Self::EmailExists => (StatusCode::BAD_REQUEST, "You already have an account".to_string()),
+            Self::UserDeactivated => (StatusCode::BAD_REQUEST, "User deactivated".to_string()),
+            Self::WrongPassword => (StatusCode::UNAUTHORIZED, "Wrong Password".to_string()),
+            Self::Forbidden(permission) => (StatusCode::FORBIDDEN, format!("You need the {} permission for this request", permission)),
+            Self::InvalidToken => (StatusCode::UNAUTHORIZED, "Token is not valid".to_string()),
+            Self::MaxGames => (StatusCode::TOO_MANY_REQUESTS, "You already have the maximum amount of active games on this server".to_string()),
+            Self::IllegalBoardSize(min, max) => (StatusCode::BAD_REQUEST, format!("Board Size must be between {} and {}", min, max)),
+            Self::InvalidPlayers(min, max) => (StatusCode::BAD_REQUEST, format!("Number of players must be between {} and {}", min, max)),
+            Self::GameNotActive => (StatusCode::BAD_REQUEST, "Game is not active".to_string()),
+            Self::InvalidGame => (StatusCode::BAD_REQUEST, "Invalid Game".to_string()),
+            Self::VerificationFailure => (StatusCode::BAD_REQUEST, "Verification failed".to_string()),
+            Self::InvalidPlayer => (StatusCode::BAD_REQUEST, "Invalid Player".to_string()),
+            Self::NotPlacing => (StatusCode::BAD_REQUEST, "Ships can only be placed while placing".to_string()),
+            Self::UnknownShipClass => (StatusCode::BAD_REQUEST, "Unknown ship class".to_string()),
+            Self::ShipPlaced => (StatusCode::BAD_REQUEST, "A ship of this class is already placed".to_string()),
+            Self::ShipOutOfBounds => (StatusCode::BAD_REQUEST, "Ship does not fit on the board".to_string()),
+            Self::ShipOverlap => (StatusCode::BAD_REQUEST, "Ship overlaps with another ship".to_string()),
+            Self::NotYourTurn => (StatusCode::BAD_REQUEST, "It is not your turn".to_string()),
+            Self::ShotOutOfBounds => (StatusCode::BAD_REQUEST, "Shot is outside the board".to_string()),
+            Self::AlreadyShot => (StatusCode::BAD_REQUEST, "You already fired at this cell".to_string()),
+            Self::GameFull => (StatusCode::BAD_REQUEST, "Game already has the maximum amount of players".to_string()),
+            Self::InvalidTransition => (StatusCode::CONFLICT, "The game changed in the meantime, please try again".to_string()),
+            Self::InvalidRules(reason) => (StatusCode::BAD_REQUEST, format!("Invalid rules: {}", reason)),
+            Self::ShipTouching => (StatusCode::BAD_REQUEST, "Ships are not allowed to touch each other".to_string()),
+            Self::UnknownRole(role) => (StatusCode::BAD_REQUEST, format!("Unknown role: {}", role)),
+            Self::JobNotFound => (StatusCode::NOT_FOUND, "Job not found, or it is running".to_string()),
+            Self::ShuttingDown => (StatusCode::SERVICE_UNAVAILABLE, "The server is shutting down, no new games can be created".to_string()),
+            Self::TooManyAttempts(seconds) => {
+                let message = format!("Too many attempts, please try again in {} seconds", seconds);
+                return (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, seconds.to_string())], Json(json!({"error": message}))).into_response();
+            },
+            Self::WeakPassword(violations) => {
+                let message = "The password does not comply with the password policy";
+                return (StatusCode::BAD_REQUEST, Json(json!({"error": message, "violations": violations}))).into_response();
+            },
         };
         (status, Json(json!({"error": error_message}))).into_response()
     }
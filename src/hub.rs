@@ -0,0 +1,86 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+use tokio::sync::broadcast;
+use log::error;
+use crate::models::event::GameEvent;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// The event hub. Every game that has WebSocket clients has a broadcast channel, the events are published after
+// the transaction of the transition is committed so a client that fetches the status sees the same state. The
+// channel is removed when the game ends, which closes the WebSockets, or when it has no clients left. Events are
+// only kept in memory: a client that connects later or falls behind fetches the status of the game.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// The amount of events a slow client can fall behind before it misses events
+const CAPACITY: usize = 64;
+
+#[derive(Default)]
+pub struct Hub {
+    games: Mutex<HashMap<u32, broadcast::Sender<String>>>,
+}
+
+impl Hub {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Hub::default())
+    }
+
+    // Receive the events of a game as json
+    pub fn subscribe(&self, game_id: u32) -> broadcast::Receiver<String> {
+        let mut games = self.games.lock().unwrap();
+        games.retain(|_, sender| sender.receiver_count() > 0);
+        games.entry(game_id)
+            .or_insert_with(|| broadcast::channel(CAPACITY).0)
+            .subscribe()
+    }
+
+    // Send an event to the clients of the game. The last event of a game closes its channel
+    pub fn publish(&self, game_id: u32, event: &GameEvent) {
+        let json = match serde_json::to_string(event) {
+            Ok(json) => json,
+            Err(err) => {
+                error!("Error encoding event {:?}: {:?}", event, err);
+                return;
+            }
+        };
+
+        let mut games = self.games.lock().unwrap();
+        let delivered = games.get(&game_id).map(|sender| sender.send(json).is_ok()).unwrap_or(false);
+        if !delivered || matches!(event, GameEvent::GameFinished { .. }) {
+            games.remove(&game_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::game::GameStatus;
+
+    #[tokio::test]
+    async fn events_reach_the_clients_of_the_game() {
+        let hub = Hub::new();
+        let mut first = hub.subscribe(1);
+        let mut other = hub.subscribe(2);
+
+        hub.publish(1, &GameEvent::TurnChanged { player_id: 2 });
+        assert_eq!(first.recv().await.unwrap(), r#"{"event":"turn_changed","player_id":2}"#);
+        assert!(other.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn finished_game_closes_the_channel() {
+        let hub = Hub::new();
+        let mut client = hub.subscribe(1);
+
+        hub.publish(1, &GameEvent::GameFinished { status: GameStatus::Finished, winner: Some(1) });
+        assert!(client.recv().await.unwrap().contains("game_finished"));
+        assert_eq!(client.recv().await, Err(broadcast::error::RecvError::Closed));
+    }
+
+    #[test]
+    fn games_without_clients_are_removed() {
+        let hub = Hub::new();
+        drop(hub.subscribe(1));
+        hub.publish(1, &GameEvent::PlacingDone { player_id: 1 });
+        assert!(hub.games.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,370 @@
+use sqlx::{Executor, MySql, MySqlPool, Transaction};
+use log::{error, info, warn};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{env, sync::Arc};
+use crate::models::job::*;
+use crate::mail::{Mailer, template::Template};
+use crate::errors::CustomError;
+use crate::models::event::GameEvent;
+use crate::hub::Hub;
+use crate::{ai, state_machine};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Background jobs. Work that should not be done inside a request, or not right now, is stored in the job table
+// and picked up by a pool of workers started from main ($JOB_WORKERS, default 4):
+// - a job runs at run_at, workers claim it with SKIP LOCKED so every job runs once
+// - a job that fails is tried again with exponential backoff, after MAX_ATTEMPTS it is dead until an admin
+//   retries it
+// - jobs about a game are queued by the state machine in the transaction of the transition and are cancelled
+//   when the turn passes or the game ends
+// Jobs that were running when the server stopped are picked up again at startup.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+const DEFAULT_WORKERS: usize = 4;
+
+// How long an idle worker waits before looking for work again
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+// A job is dead after this many failed attempts
+const MAX_ATTEMPTS: u8 = 5;
+
+// The wait after the first failure in seconds, doubled after every next failure
+const BACKOFF: i64 = 30;
+
+// What is shown instead of a secret in a listed payload
+const REDACTED: &str = "***";
+
+// What a job does, stored as json in the payload
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(tag = "task", rename_all = "snake_case")]
+pub enum Task {
+    Mail {
+        display_name: String,
+        email_address: String,
+        locale: String,
+        template: Template,
+        values: Vec<(String, String)>,
+    },
+    AiMove { game_id: u32, player_id: u8 },
+    TurnTimeout { game_id: u32, player_id: u8 },
+}
+
+impl Task {
+    // A mail from a template, see Mailer::send_template
+    pub fn mail(display_name: &str, email_address: &str, locale: &str, template: Template, values: &[(&str, &str)]) -> Task {
+        Task::Mail {
+            display_name: display_name.to_string(),
+            email_address: email_address.to_string(),
+            locale: locale.to_string(),
+            template,
+            values: values.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        }
+    }
+
+    fn kind(&self) -> JobKind {
+        match self {
+            Task::Mail { .. } => JobKind::Mail,
+            Task::AiMove { .. } => JobKind::AiMove,
+            Task::TurnTimeout { .. } => JobKind::TurnTimeout,
+        }
+    }
+
+    fn game_id(&self) -> Option<u32> {
+        match self {
+            Task::Mail { .. } => None,
+            Task::AiMove { game_id, .. } | Task::TurnTimeout { game_id, .. } => Some(*game_id),
+        }
+    }
+}
+
+// The payload as admins may see it. Mails carry addresses and codes, those are masked
+pub fn redacted(payload: &str) -> String {
+
+    match serde_json::from_str(payload) {
+        Ok(Task::Mail { display_name, locale, template, values, .. }) => {
+            let task = Task::Mail {
+                display_name,
+                email_address: REDACTED.to_string(),
+                locale,
+                template,
+                values: values.into_iter().map(|(name, _)| (name, REDACTED.to_string())).collect(),
+            };
+            serde_json::to_string(&task).unwrap_or_else(|_| REDACTED.to_string())
+        },
+        Ok(_) => payload.to_string(),
+        Err(_) => REDACTED.to_string(),
+    }
+}
+
+// Queue a task to run at run_at, on the pool or within the transaction of the caller
+pub async fn enqueue<'e, E>(executor: E, task: &Task, run_at: DateTime<Utc>) -> Result<(), CustomError>
+    where E: Executor<'e, Database = MySql> {
+
+    let payload = serde_json::to_string(task).map_err(|err| {
+        error!("Error encoding job {:?}: {:?}", task, err);
+        CustomError::InternalServerError
+    })?;
+
+    let sql = "INSERT INTO job (kind, game_id, payload, run_at) VALUES (?, ?, ?, ?)";
+    sqlx::query(sql)
+        .bind(task.kind())
+        .bind(task.game_id())
+        .bind(payload)
+        .bind(run_at)
+        .execute(executor)
+        .await
+        .map_err(|err| {
+            error!("Error queueing job {:?}: {:?}", task.kind(), err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(())
+}
+
+// Cancel the jobs of a game that didn't die, they are about a turn that is over
+pub async fn cancel(tx: &mut Transaction<'_, MySql>, game_id: u32) -> Result<(), CustomError> {
+
+    let sql = "DELETE FROM job WHERE game_id = ? AND status <> ?";
+    sqlx::query(sql)
+        .bind(game_id)
+        .bind(JobStatus::Dead)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error cancelling jobs of game {}: {:?}", game_id, err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(())
+}
+
+// Start the workers. Jobs that were running when the server stopped are released first
+pub async fn start(pool: &MySqlPool, mailer: Arc<dyn Mailer>, hub: Arc<Hub>) {
+
+    let workers = match env::var("JOB_WORKERS") {
+        Ok(value) => value.parse().expect("$JOB_WORKERS is not numeric"),
+        Err(_) => DEFAULT_WORKERS,
+    };
+
+    let sql = "UPDATE job SET status = ? WHERE status = ?";
+    match sqlx::query(sql)
+        .bind(JobStatus::Pending)
+        .bind(JobStatus::Running)
+        .execute(pool)
+        .await {
+            Ok(result) => info!("{} interrupted jobs released", result.rows_affected()),
+            Err(err) => error!("Error releasing interrupted jobs: {:?}", err),
+    }
+
+    for _ in 0..workers {
+        tokio::spawn(work(pool.clone(), mailer.clone(), hub.clone()));
+    }
+    info!("{} job workers started", workers);
+}
+
+async fn work(pool: MySqlPool, mailer: Arc<dyn Mailer>, hub: Arc<Hub>) {
+
+    loop {
+        match claim(&pool).await {
+            Ok(Some(job)) => {
+                let result = run(&pool, &*mailer, &hub, &job).await;
+                if let Err(err) = finish(&pool, &job, result).await {
+                    error!("Error finishing job {}: {:?}", job.id, err);
+                }
+            },
+            Ok(None) | Err(_) => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+}
+
+// Take the first job that is due, other workers skip it while it is claimed
+async fn claim(pool: &MySqlPool) -> Result<Option<Job>, CustomError> {
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error claiming job: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let sql = "SELECT * FROM job WHERE status = ? AND run_at <= ? ORDER BY run_at, id LIMIT 1 FOR UPDATE SKIP LOCKED";
+    let job: Option<Job> = sqlx::query_as(sql)
+        .bind(JobStatus::Pending)
+        .bind(Utc::now())
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error claiming job: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let mut job = match job {
+        Some(job) => job,
+        None => return Ok(None),
+    };
+
+    let sql = "UPDATE job SET status = ?, attempts = attempts + 1 WHERE id = ?";
+    sqlx::query(sql)
+        .bind(JobStatus::Running)
+        .bind(job.id)
+        .execute(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error claiming job {}: {:?}", job.id, err);
+            CustomError::InternalServerError
+        })?;
+
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error claiming job {}: {:?}", job.id, err);
+            CustomError::InternalServerError
+        })?;
+
+    job.status = JobStatus::Running;
+    job.attempts += 1;
+    Ok(Some(job))
+}
+
+// Do the work, the error is kept with the job
+async fn run(pool: &MySqlPool, mailer: &dyn Mailer, hub: &Hub, job: &Job) -> Result<(), String> {
+
+    let task: Task = serde_json::from_str(&job.payload).map_err(|err| format!("Invalid payload: {}", err))?;
+
+    match task {
+        Task::Mail { display_name, email_address, locale, template, values } => {
+            let values: Vec<(&str, &str)> = values.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+            mailer.send_template(&display_name, &email_address, &locale, template, &values).await
+        },
+        Task::AiMove { game_id, player_id } => ai::take_turn(pool, hub, game_id, player_id).await,
+        Task::TurnTimeout { game_id, player_id } => turn_timeout(pool, hub, job.id, game_id, player_id).await,
+    }
+    .map_err(|err| format!("{:?}", err))
+}
+
+// The player took too long, the turn passes on. The job is gone when the turn already passed in the meantime
+async fn turn_timeout(pool: &MySqlPool, hub: &Hub, job_id: u64, game_id: u32, player_id: u8) -> Result<(), CustomError> {
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error timing out turn: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let game = state_machine::lock_game(&mut tx, game_id).await?;
+
+    let sql = "SELECT COUNT(*) FROM job WHERE id = ?";
+    let (current,): (i64,) = sqlx::query_as(sql)
+        .bind(job_id)
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error checking job {}: {:?}", job_id, err);
+            CustomError::InternalServerError
+        })?;
+
+    if current == 0 {
+        info!("Turn of player {} in game {} already over", player_id, game_id);
+        return Ok(());
+    }
+
+    let next = state_machine::turn_timed_out(&mut tx, &game, player_id).await?;
+
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error timing out turn: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    if let Some(next) = next {
+        hub.publish(game_id, &GameEvent::TurnChanged { player_id: next.player_id });
+    }
+    Ok(())
+}
+
+// A job that succeeded is removed. A job that failed is tried again later, or dies after MAX_ATTEMPTS
+async fn finish(pool: &MySqlPool, job: &Job, result: Result<(), String>) -> Result<(), CustomError> {
+
+    let query = match result {
+        Ok(()) => {
+            info!("Job {} ({:?}) done", job.id, job.kind);
+            sqlx::query("DELETE FROM job WHERE id = ? AND status = ?")
+                .bind(job.id)
+                .bind(JobStatus::Running)
+        },
+        Err(message) if job.attempts >= MAX_ATTEMPTS => {
+            error!("Job {} ({:?}) failed {} times, giving up: {}", job.id, job.kind, job.attempts, message);
+            sqlx::query("UPDATE job SET status = ?, last_error = ? WHERE id = ? AND status = ?")
+                .bind(JobStatus::Dead)
+                .bind(message)
+                .bind(job.id)
+                .bind(JobStatus::Running)
+        },
+        Err(message) => {
+            let run_at = Utc::now() + backoff(job.attempts);
+            warn!("Job {} ({:?}) failed, trying again at {}: {}", job.id, job.kind, run_at, message);
+            sqlx::query("UPDATE job SET status = ?, run_at = ?, last_error = ? WHERE id = ? AND status = ?")
+                .bind(JobStatus::Pending)
+                .bind(run_at)
+                .bind(message)
+                .bind(job.id)
+                .bind(JobStatus::Running)
+        },
+    };
+
+    query.execute(pool)
+        .await
+        .map_err(|err| {
+            error!("Error updating job {}: {:?}", job.id, err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(())
+}
+
+// The wait before the next attempt, after the given number of attempts
+fn backoff(attempts: u8) -> Duration {
+    Duration::seconds(BACKOFF << attempts.saturating_sub(1).min(10))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles() {
+        assert_eq!(backoff(1), Duration::seconds(30));
+        assert_eq!(backoff(2), Duration::seconds(60));
+        assert_eq!(backoff(4), Duration::seconds(240));
+    }
+
+    #[test]
+    fn tasks_survive_the_payload() {
+        let task = Task::mail("Bob", "bob@example.org", "nl", Template::YourTurn, &[("game_id", "7")]);
+        let payload = serde_json::to_string(&task).unwrap();
+        assert!(payload.contains(r#""task":"mail""#));
+        assert!(payload.contains(r#""template":"your_turn""#));
+        assert_eq!(serde_json::from_str::<Task>(&payload).unwrap(), task);
+
+        let task = Task::TurnTimeout { game_id: 7, player_id: 2 };
+        assert_eq!(task.kind(), JobKind::TurnTimeout);
+        assert_eq!(task.game_id(), Some(7));
+        assert_eq!(serde_json::from_str::<Task>(&serde_json::to_string(&task).unwrap()).unwrap(), task);
+    }
+
+    #[test]
+    fn mail_payloads_are_redacted() {
+        let task = Task::mail("Bob", "bob@example.org", "nl", Template::Verification, &[("code", "123456")]);
+        let payload = redacted(&serde_json::to_string(&task).unwrap());
+        assert!(!payload.contains("bob@example.org"));
+        assert!(!payload.contains("123456"));
+        assert!(payload.contains(r#"["code","***"]"#));
+
+        let payload = serde_json::to_string(&Task::AiMove { game_id: 7, player_id: 2 }).unwrap();
+        assert_eq!(redacted(&payload), payload);
+        assert_eq!(redacted("not json"), REDACTED);
+    }
+}
@@ -0,0 +1,281 @@
+use axum::async_trait;
+use lettre::{
+    message::{Mailbox, MultiPart}, transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use log::{error, info};
+use std::{env, path::PathBuf, sync::{Arc, Mutex}};
+use chrono::Utc;
+use crate::errors::CustomError;
+
+pub mod template;
+use template::{Email, Locale, Template};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Sending mail. The transport is chosen at startup with $MAIL_TRANSPORT:
+// - smtp (default): through the SMTP server in $SMTP_HOST using STARTTLS
+// - file: every message is written as an .eml file to the directory in $MAIL_DIRECTORY
+// - memory: messages are logged and kept in memory, for running without a mail server and for tests
+// All settings are read once, a missing setting stops the server at startup. $EMAIL_FROM, $EMAIL_REPLY_TO_NAME and
+// $EMAIL_REPLY_TO_ADDRESS are only needed for smtp, the other transports fall back on a local sender. Messages are made from the templates
+// in the language of the recipient, with a plain text and an HTML version.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Who the mail comes from
+#[derive(Clone)]
+pub struct Sender {
+    pub from: Mailbox,
+    pub reply_to: Mailbox,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+
+    // The sender of every message
+    fn sender(&self) -> &Sender;
+
+    // Deliver a complete message
+    async fn deliver(&self, message: Message) -> Result<(), CustomError>;
+
+    // Compose a message from the sender to the recipient and deliver it
+    async fn send(&self, display_name: &str, email_address: &str, email: Email) -> Result<(), CustomError> {
+
+        let to = email_address.parse()
+            .map(|address| Mailbox::new(Some(display_name.to_string()), address))
+            .map_err(|err| {
+                error!("Invalid email address {}: {:?}", email_address, err);
+                CustomError::InternalServerError
+            })?;
+
+        let message = Message::builder()
+            .from(self.sender().from.clone())
+            .reply_to(self.sender().reply_to.clone())
+            .to(to)
+            .subject(email.subject)
+            .multipart(MultiPart::alternative_plain_html(email.text, email.html))
+            .map_err(|err| {
+                error!("Error building mail message: {:?}", err);
+                CustomError::InternalServerError
+            })?;
+
+        self.deliver(message).await
+    }
+
+    // Render the template in the language of the recipient and send it. The display name is filled in already
+    async fn send_template(&self, display_name: &str, email_address: &str, locale: &str,
+                           template: Template, values: &[(&str, &str)]) -> Result<(), CustomError> {
+
+        let mut values = values.to_vec();
+        values.push(("display_name", display_name));
+        let email = template::render(template, Locale::from_tag(locale), &values);
+        self.send(display_name, email_address, email).await
+    }
+}
+
+// Create the mailer from the environment
+pub fn from_env() -> Arc<dyn Mailer> {
+
+    template::check();
+
+    let transport = env::var("MAIL_TRANSPORT").unwrap_or_else(|_| "smtp".to_string());
+    let setting = |name: &str, default: &str| match env::var(name) {
+        Ok(value) => value,
+        Err(_) if transport != "smtp" => default.to_string(),
+        Err(_) => panic!("${} is not set", name),
+    };
+
+    let sender = Sender {
+        from: setting("EMAIL_FROM", "Sea Battle <seabattle@localhost>")
+            .parse().expect("$EMAIL_FROM is not a valid address"),
+        reply_to: Mailbox::new(
+            Some(setting("EMAIL_REPLY_TO_NAME", "Sea Battle")),
+            setting("EMAIL_REPLY_TO_ADDRESS", "seabattle@localhost")
+                .parse().expect("$EMAIL_REPLY_TO_ADDRESS is not a valid address")),
+    };
+
+    match transport.as_str() {
+        "smtp" => Arc::new(SmtpMailer::from_env(sender)),
+        "file" => Arc::new(FileMailer::new(sender, env::var("MAIL_DIRECTORY").expect("$MAIL_DIRECTORY is not set").into())),
+        "memory" => Arc::new(MemoryMailer::new(sender)),
+        other => panic!("$MAIL_TRANSPORT {} is not one of smtp, file or memory", other),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// SMTP server
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub struct SmtpMailer {
+    sender: Sender,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    fn from_env(sender: Sender) -> SmtpMailer {
+        let smtp_host = env::var("SMTP_HOST").expect("$SMTP_HOST is not set");
+        let creds = Credentials::new(
+            env::var("SMTP_USERNAME").expect("$SMTP_USERNAME is not set"),
+            env::var("SMTP_PASSWORD").expect("$SMTP_PASSWORD is not set"));
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp_host)
+            .expect("$SMTP_HOST is not a valid host")
+            .credentials(creds)
+            .build();
+        SmtpMailer { sender, transport }
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+
+    fn sender(&self) -> &Sender {
+        &self.sender
+    }
+
+    async fn deliver(&self, message: Message) -> Result<(), CustomError> {
+        self.transport.send(message)
+            .await
+            .map(|_| ())
+            .map_err(|err| {
+                error!("Error while sending mail: {:?}", err);
+                CustomError::InternalServerError
+            })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Directory with .eml files, named after the time they were written
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub struct FileMailer {
+    sender: Sender,
+    directory: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(sender: Sender, directory: PathBuf) -> FileMailer {
+        FileMailer { sender, directory }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+
+    fn sender(&self) -> &Sender {
+        &self.sender
+    }
+
+    async fn deliver(&self, message: Message) -> Result<(), CustomError> {
+        let file_name = format!("{}-{:08x}.eml", Utc::now().format("%Y%m%d%H%M%S%.6f"), rand::random::<u32>());
+        let path = self.directory.join(file_name);
+        tokio::fs::write(&path, message.formatted())
+            .await
+            .map_err(|err| {
+                error!("Error writing mail to {:?}: {:?}", path, err);
+                CustomError::InternalServerError
+            })?;
+        info!("Mail written to {:?}", path);
+        Ok(())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Memory, the last messages are kept so tests can look at them
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+const MEMORY_MESSAGES: usize = 100;
+
+pub struct MemoryMailer {
+    sender: Sender,
+    messages: Mutex<Vec<Message>>,
+}
+
+impl MemoryMailer {
+    pub fn new(sender: Sender) -> MemoryMailer {
+        MemoryMailer { sender, messages: Mutex::new(Vec::new()) }
+    }
+
+    // The messages delivered so far
+    #[cfg(test)]
+    pub fn messages(&self) -> Vec<Message> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+
+    fn sender(&self) -> &Sender {
+        &self.sender
+    }
+
+    async fn deliver(&self, message: Message) -> Result<(), CustomError> {
+        // The body can hold a verification code, only the recipient and the subject are logged
+        info!("Mail to {:?} kept in memory: {}", message.envelope().to(), message.headers().get_raw("Subject").unwrap_or_default());
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == MEMORY_MESSAGES {
+            messages.remove(0);
+        }
+        messages.push(message);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender() -> Sender {
+        Sender {
+            from: "Sea Battle <server@example.org>".parse().unwrap(),
+            reply_to: "Admin <admin@example.org>".parse().unwrap(),
+        }
+    }
+
+    fn email() -> Email {
+        Email { subject: "Hello".to_string(), text: "Body".to_string(), html: "<p>Body</p>".to_string() }
+    }
+
+    #[tokio::test]
+    async fn memory_mailer_keeps_messages() {
+        let mailer = MemoryMailer::new(sender());
+        mailer.send("Alice", "alice@example.org", email()).await.unwrap();
+
+        let messages = mailer.messages();
+        assert_eq!(messages.len(), 1);
+        let formatted = String::from_utf8(messages[0].formatted()).unwrap();
+        assert!(formatted.contains("To: Alice <alice@example.org>"));
+        assert!(formatted.contains("Subject: Hello"));
+        assert!(formatted.contains("multipart/alternative"));
+    }
+
+    #[tokio::test]
+    async fn templates_use_the_language_of_the_recipient() {
+        let mailer = MemoryMailer::new(sender());
+        mailer.send_template("Bob", "bob@example.org", "nl", Template::Verification, &[("code", "42")]).await.unwrap();
+
+        let formatted = String::from_utf8(mailer.messages()[0].formatted()).unwrap();
+        assert!(formatted.contains("Subject: Je verificatiecode voor Zeeslag"));
+    }
+
+    #[tokio::test]
+    async fn file_mailer_writes_eml() {
+        let directory = env::temp_dir().join(format!("seabattle-mail-{}", rand::random::<u32>()));
+        std::fs::create_dir(&directory).unwrap();
+        let mailer = FileMailer::new(sender(), directory.clone());
+        mailer.send("Alice", "alice@example.org", email()).await.unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let formatted = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(formatted.contains("To: Alice <alice@example.org>"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_address_is_refused() {
+        let mailer = MemoryMailer::new(sender());
+        assert!(mailer.send("Alice", "not an address", email()).await.is_err());
+        assert!(mailer.messages().is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Mail templates. Every template has a plain text and an HTML version per language in templates/mail/<locale>/,
+// they are compiled into the server. The first line of the text version is the subject. Placeholders look like
+// {{name}}, in the HTML version the values are escaped.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+use serde::{Deserialize, Serialize};
+
+// Stored by name in mail jobs
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Template {
+    Verification,
+    PasswordChanged,
+    YourTurn,
+    GameInvitation,
+    GameOver,
+    AccountDeactivated,
+    PlayerJoined,
+    GameAborted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Locale {
+    En,
+    Nl,
+}
+
+// A rendered template, ready to be sent
+pub struct Email {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+impl Template {
+    pub const ALL: [Template; 8] = [Template::Verification, Template::PasswordChanged, Template::YourTurn,
+                                    Template::GameInvitation, Template::GameOver, Template::AccountDeactivated,
+                                    Template::PlayerJoined, Template::GameAborted];
+
+    // The placeholders of the template, the display name of the recipient is always available
+    fn placeholders(&self) -> &'static [&'static str] {
+        match self {
+            Template::Verification => &["display_name", "code"],
+            Template::PasswordChanged => &["display_name"],
+            Template::YourTurn => &["display_name", "game_id"],
+            Template::GameInvitation => &["display_name", "game_id", "host"],
+            Template::GameOver => &["display_name", "game_id", "winner", "score"],
+            Template::AccountDeactivated => &["display_name"],
+            Template::PlayerJoined => &["display_name", "game_id", "players"],
+            Template::GameAborted => &["display_name", "game_id"],
+        }
+    }
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::En, Locale::Nl];
+
+    // The locale of a language tag like "nl" or "nl-BE", English when there are no templates for the language
+    pub fn from_tag(tag: &str) -> Locale {
+        Locale::parse(tag).unwrap_or(Locale::En)
+    }
+
+    // The language tag stored with the user
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Nl => "nl",
+        }
+    }
+
+    // The locale of a language tag, None when there are no templates for the language
+    pub fn parse(tag: &str) -> Option<Locale> {
+        match tag.split(['-', '_']).next().unwrap_or("").to_lowercase().as_str() {
+            "en" => Some(Locale::En),
+            "nl" => Some(Locale::Nl),
+            _ => None,
+        }
+    }
+}
+
+// The text and HTML version of every template in every language
+macro_rules! sources {
+    ($($template:ident => $file:literal),*) => {
+        fn sources(template: Template, locale: Locale) -> (&'static str, &'static str) {
+            match (template, locale) {
+                $(
+                    (Template::$template, Locale::En) => (
+                        include_str!(concat!("../../templates/mail/en/", $file, ".txt")),
+                        include_str!(concat!("../../templates/mail/en/", $file, ".html"))),
+                    (Template::$template, Locale::Nl) => (
+                        include_str!(concat!("../../templates/mail/nl/", $file, ".txt")),
+                        include_str!(concat!("../../templates/mail/nl/", $file, ".html"))),
+                )*
+            }
+        }
+    };
+}
+
+sources!(
+    Verification => "verification",
+    PasswordChanged => "password_changed",
+    YourTurn => "your_turn",
+    GameInvitation => "game_invitation",
+    GameOver => "game_over",
+    AccountDeactivated => "account_deactivated",
+    PlayerJoined => "player_joined",
+    GameAborted => "game_aborted"
+);
+
+// Fill in the placeholders of the template in the language of the locale
+pub fn render(template: Template, locale: Locale, values: &[(&str, &str)]) -> Email {
+
+    let (text, html) = sources(template, locale);
+    let (mut text, mut html) = (text.to_string(), html.to_string());
+    for (name, value) in values {
+        let placeholder = format!("{{{{{}}}}}", name);
+        text = text.replace(&placeholder, value);
+        html = html.replace(&placeholder, &escape(value));
+    }
+
+    let (subject, body) = text.split_once('\n').unwrap_or((&text, ""));
+    Email {
+        subject: subject.trim_start_matches("Subject:").trim().to_string(),
+        text: body.trim_start().to_string(),
+        html,
+    }
+}
+
+// Check that every template only uses its own placeholders, so a mistake in a template stops the server at
+// startup instead of sending mail with placeholders in it
+pub fn check() {
+    for template in Template::ALL {
+        for locale in Locale::ALL {
+            let values: Vec<(&str, &str)> = template.placeholders().iter().map(|name| (*name, "x")).collect();
+            let email = render(template, locale, &values);
+            if email.subject.is_empty() || [&email.subject, &email.text, &email.html].iter().any(|part| part.contains("{{")) {
+                panic!("Mail template {:?} for {:?} has no subject or an unknown placeholder", template, locale);
+            }
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_template_is_complete() {
+        check();
+    }
+
+    #[test]
+    fn values_are_filled_in_and_escaped() {
+        let email = render(Template::Verification, Locale::Nl, &[("display_name", "<b>Bob</b>"), ("code", "1234")]);
+        assert_eq!(email.subject, "Je verificatiecode voor Zeeslag");
+        assert!(email.text.starts_with("Hallo <b>Bob</b>,"));
+        assert!(email.text.contains("1234"));
+        assert!(email.html.contains("&lt;b&gt;Bob&lt;/b&gt;"));
+    }
+
+    #[test]
+    fn unknown_languages_fall_back_to_english() {
+        assert_eq!(Locale::from_tag("nl-BE"), Locale::Nl);
+        assert_eq!(Locale::from_tag("fr"), Locale::En);
+        assert_eq!(Locale::parse("fr"), None);
+    }
+}
@@ -1,31 +1,39 @@
-use axum::{ extract::{Extension},routing::{get, post, put, delete}, Router, 
-    headers::{Authorization, authorization::Bearer}
-};
-use sqlx::{mysql::{MySqlPool, MySqlConnectOptions, MySqlPoolOptions, MySqlArguments}, ConnectOptions};
-use std::{env, net::SocketAddr};
-use log::{debug, error, log_enabled, info, Level};
-use serde::{Deserialize, Serialize};
+use axum::{ extract::{Extension}, middleware, routing::{delete, get, post, put}, Router};
+use sqlx::mysql::MySqlPool;
+use std::{env, net::SocketAddr, sync::Arc};
+use log::{debug, info};
 use simplelog::*;
+use chrono::{DateTime, Local};
 mod errors;
 mod controllers;
 mod models;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation, TokenData};
-use crate::errors::CustomError;
+mod state_machine;
+mod ai;
+mod auth;
+mod verification;
+mod shutdown;
+mod rate_limit;
+mod password;
+mod mail;
+mod notification;
+mod job;
+mod hub;
+mod pagination;
 
-// The claims struct used for creating a Bearer token
-#[derive(Deserialize, Serialize, Debug)]
-struct Claims {
-    sub: String,
-    iat: usize,
-    exp: usize,
-    admin: bool,
-}
-
-// Shared immutable state
+// Shared state. Everything is immutable, except for the shutdown signals, the rate limit buckets and the channels
+// of the event hub
 #[derive(Clone)]
 pub struct AppState {
     pub jwt_secret: String,
     pub token_duration: i64,
+    pub refresh_token_duration: i64,
+    pub started: DateTime<Local>,
+    pub shutdown: Arc<shutdown::Shutdown>,
+    pub limiter: Arc<rate_limit::RateLimiter>,
+    pub hasher: password::Hasher,
+    pub policy: password::Policy,
+    pub mailer: Arc<dyn mail::Mailer>,
+    pub hub: Arc<hub::Hub>,
 }
 
 #[tokio::main]
@@ -51,24 +59,66 @@ async fn main() -> anyhow::Result<()> {
     let state = AppState {
         jwt_secret: env::var("JWT_SECRET").expect("$JWT_SECRET is not set"),
         token_duration: env::var("TOKEN_DURATION").expect("$TOKEN_DURATION is not set")
-            .parse::<i64>().expect("$TOKEN_DURATION is not numeric")
+            .parse::<i64>().expect("$TOKEN_DURATION is not numeric"),
+        refresh_token_duration: env::var("REFRESH_TOKEN_DURATION").expect("$REFRESH_TOKEN_DURATION is not set")
+            .parse::<i64>().expect("$REFRESH_TOKEN_DURATION is not numeric"),
+        started: Local::now(),
+        shutdown: shutdown::Shutdown::new(),
+        limiter: Arc::new(rate_limit::RateLimiter::new()),
+        hasher: password::Hasher::from_env(),
+        policy: password::Policy::from_env(),
+        mailer: mail::from_env(),
+        hub: hub::Hub::new(),
     };
+    let shutdown = state.shutdown.clone();
+    let hub = state.hub.clone();
 
-    // Define routes
-    let app = Router::new()
+    // Mail, moves of the server and turn timeouts are done by the job workers, game notifications are batched
+    // into mail jobs
+    job::start(&pool, state.mailer.clone(), state.hub.clone()).await;
+    tokio::spawn(notification::run(pool.clone()));
+
+    // Routes where passwords and verification codes can be guessed are rate limited
+    let limited = Router::new()
         .route("/login", get(controllers::user::login))
         .route("/signup", post(controllers::user::signup))
         .route("/signup/verification", post(controllers::user::signup_verification))
+        .route("/user/:id/verification", post(controllers::user::password_verification))
+        .route("/me/email/verification", post(controllers::user::email_verification))
+        .route("/password/forgot", post(controllers::user::forgot_password))
+        .route("/password/reset", post(controllers::user::reset_password))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::limit));
+
+    // Define routes
+    let app = Router::new()
+        .merge(limited)
+        .route("/token/refresh", post(controllers::user::refresh_token))
+        .route("/logout", post(controllers::user::logout))
         .route("/user", post(controllers::user::new_user))
         .route("/user/:id", get(controllers::user::get_user).post(controllers::user::update_user))
+        .route("/user/:id/role", put(controllers::user::set_user_roles))
+        .route("/me", get(controllers::user::get_me).patch(controllers::user::update_me))
+        .route("/role", get(controllers::user::list_roles))
         .route("/user/:id/password", put(controllers::user::change_password))
-        .route("/user/:id/verification", post(controllers::user::password_verification))
         .route("/motd",get(controllers::server::get_motd))
         .route("/server/motd", post(controllers::server::set_motd))
-        .route("/game", post(controllers::game::new_game))
-        .route("/game/:game_id", post(controllers::game::join_game))
+        .route("/server/shutdown", post(controllers::server::shutdown))
+        .route("/server/kill", post(controllers::server::kill))
+        .route("/server/status", get(controllers::server::server_status))
+        .route("/server/highscore", get(controllers::server::highscore))
+        .route("/server/rules", get(controllers::server::get_rules).put(controllers::server::set_rules))
+        .route("/server/job", get(controllers::server::list_jobs))
+        .route("/server/job/:id", delete(controllers::server::delete_job))
+        .route("/server/job/:id/retry", post(controllers::server::retry_job))
+        .route("/game", get(controllers::game::list_games).post(controllers::game::new_game))
+        .route("/game/server", post(controllers::game::add_server))
+        .route("/game/:game_id", post(controllers::game::join_game).delete(controllers::game::abort_game))
+        .route("/game/:game_id/status", get(controllers::game::game_status))
+        .route("/game/:game_id/ws", get(controllers::game::game_events))
+        .route("/game/:game_id/player/:id/ship", post(controllers::board::place_ship))
+        .route("/game/:game_id/player/:id/shot", post(controllers::board::fire_shot))
         .with_state(state)
-        .layer(Extension(pool));
+        .layer(Extension(pool.clone()));
 
     // Start the server
     // TODO: tls -> there is now a axum-server crate that does this
@@ -76,35 +126,11 @@ async fn main() -> anyhow::Result<()> {
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
     debug!("Listening on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown::signal(pool, hub, shutdown))
         .await?;
+    info!("Stopped");
 
     Ok(())
 
 }
-
-// Helper function to check if a bearer token is valid (user is logged in) and if the token is an admin token if needed.
-// The JWT secret is retrieved from the state shared across all handlers (fetched from an env in main)
-// It's here because basically every controller function needs it.
-async fn check_access(state: &AppState, bearer: &Authorization<Bearer>, admin_needed: bool) -> Result<(String, bool),CustomError> {
-
-    // Decode the Bearer token from the header. When succesfull return decoded user_name (sub field)
-    match decode::<Claims>(
-        bearer.token(),
-        &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
-        &Validation::default(),
-    ) {
-        Ok(token_data) => {
-            if admin_needed && !token_data.claims.admin {
-                error!("User is not admin, request denied");
-                Err(CustomError::NotAdmin)
-            } else {
-                Ok((token_data.claims.sub, token_data.claims.admin))
-            }
-        },
-        Err(err) => {
-            error!("Invalid token: {:?}", err.kind());
-            Err(CustomError::InvalidToken)
-        }
-    }
-}
\ No newline at end of file
@@ -1,4 +1,11 @@
 pub mod server;
 pub mod user;
 pub mod game;
-pub mod board;
\ No newline at end of file
+pub mod board;
+pub mod ship;
+pub mod rules;
+pub mod role;
+pub mod verification;
+pub mod notification;
+pub mod job;
+pub mod event;
\ No newline at end of file
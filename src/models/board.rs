@@ -5,17 +5,78 @@ pub struct Board {
     pub game_id: u32,
     pub user_name: String,
     pub player_id: u8,
-    pub status: u8,
+    pub status: BoardStatus,
     pub shots_fired: u16,
     pub shots_map: Vec<u8>,
     pub score: u16,
+    pub turn_shots: u8,
 }
 
-#[derive(Deserialize, Serialize, sqlx::Type, Debug)]
+// Status of a board, stored as TINYINT UNSIGNED
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
 pub enum BoardStatus {
     Placing,
     Shooting,
     Waiting,
     Won,
     Lost,
-}
\ No newline at end of file
+}
+
+// The public part of a board, the shots_map and the ships of a player stay hidden
+#[derive(Deserialize, Serialize, sqlx::FromRow, Debug)]
+pub struct BoardSummary {
+    pub player_id: u8,
+    pub user_name: String,
+    pub status: BoardStatus,
+    pub shots_fired: u16,
+    pub score: u16,
+}
+
+impl BoardStatus {
+    // The transitions a board can make, see doc/notes.md
+    pub fn can_become(self, next: BoardStatus) -> bool {
+        matches!((self, next),
+            (BoardStatus::Placing, BoardStatus::Waiting)
+            | (BoardStatus::Waiting, BoardStatus::Shooting)
+            | (BoardStatus::Waiting, BoardStatus::Lost)
+            | (BoardStatus::Shooting, BoardStatus::Waiting)
+            | (BoardStatus::Shooting, BoardStatus::Won))
+    }
+}
+
+// The struct used for receiving a shot as json
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Shot {
+    pub x: u8,
+    pub y: u8,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum ShotOutcome {
+    Miss,
+    Hit,
+    Sunk,
+}
+
+// A ship of an opponent that was hit by a shot. The class is only revealed when the ship is sunk
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Hit {
+    pub player_id: u8,
+    pub user_name: String,
+    pub outcome: ShotOutcome,
+    pub class: Option<String>,
+    pub fleet_sunk: bool,
+}
+
+// The struct used to respond with the result of a shot. A shot is fired at the same cell on the boards of all
+// opponents that are still in the game, the outcome is the best result over all hits. next_player is the
+// player_id of the player whose turn it is now, None when the game is over.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ShotResult {
+    pub x: u8,
+    pub y: u8,
+    pub outcome: ShotOutcome,
+    pub hits: Vec<Hit>,
+    pub next_player: Option<u8>,
+}
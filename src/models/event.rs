@@ -0,0 +1,15 @@
+use serde::Serialize;
+use crate::models::{game::GameStatus, board::ShotResult};
+
+// The events of a game pushed to the WebSocket clients, as json with the name of the event in "event"
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum GameEvent {
+    PlayerJoined { player_id: u8, user_name: String },
+    PlacingDone { player_id: u8 },
+    TurnChanged { player_id: u8 },
+    ShotFired { player_id: u8, result: ShotResult },
+    PlayerEliminated { player_id: u8 },
+    GameFinished { status: GameStatus, winner: Option<u8> },
+    Lagged { missed: u64 },             // the client missed events, fetch the status of the game again
+}
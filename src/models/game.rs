@@ -1,20 +1,77 @@
 use serde::{Deserialize, Serialize};
 use chrono::Local;
+use crate::models::{board::BoardSummary, ship::Ship, rules::{Rules, RulesBlob}};
 
 #[derive(Deserialize, Serialize, sqlx::FromRow, Debug)]
 pub struct Game {
     pub id: u32,
-    pub status: u8,
+    pub status: GameStatus,
     pub board_size: u8,
     pub amount_of_players: u8,
     pub placing: chrono::DateTime<Local>,
     pub started: chrono::DateTime<Local>,
     pub finished: chrono::DateTime<Local>,
+    pub difficulty: Difficulty,
+    #[sqlx(try_from = "RulesBlob")]
+    pub rules: Rules,
 }
 
-#[derive(Deserialize, Serialize, sqlx::Type, Debug, PartialEq)]
+// The struct used to respond with the status of a game. Shooter is the player_id of the player whose turn it is,
+// ships are the ships of the requesting user (empty when he is not playing in this game).
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GameOverview {
+    pub game: Game,
+    pub boards: Vec<BoardSummary>,
+    pub shooter: Option<u8>,
+    pub ships: Vec<Ship>,
+}
+
+// A game in the list of games, with the amount of players that joined so far
+#[derive(Deserialize, Serialize, sqlx::FromRow, Debug)]
+pub struct GameListing {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub game: Game,
+    pub players: i64,
+}
+
+// The struct used for receiving the filters for listing games as query parameters.
+// Open games are active games with fewer players than amount_of_players
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GameFilter {
+    pub player: Option<String>,
+    pub status: Option<GameStatus>,
+    pub board_size: Option<u8>,
+    pub open: Option<bool>,
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
+
+// Status of a game, stored as TINYINT UNSIGNED
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
 pub enum GameStatus {
     Active,
     Finished,
     Aborted,
 }
+
+// How well the server plays when it joins the game, stored as TINYINT UNSIGNED
+//  Easy:   random shots
+//  Medium: hunt with parity, target the neighbours of a hit
+//  Hard:   probability density of all possible ship placements
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, PartialEq, Default)]
+#[repr(u8)]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Medium,
+    Hard,
+}
+
+impl GameStatus {
+    // A game can only end once
+    pub fn can_become(self, next: GameStatus) -> bool {
+        self == GameStatus::Active && next != GameStatus::Active
+    }
+}
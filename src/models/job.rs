@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+// What a job does, stored as a MySQL ENUM. The details are in the json payload
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum JobKind {
+    Mail,                   // send a mail from a template
+    AiMove,                 // the server takes its turn
+    TurnTimeout,            // the turn of a player is over
+}
+
+// Stored as a MySQL ENUM. Finished jobs are removed, dead jobs failed too often and wait for an admin
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Dead,
+}
+
+// A job in the job table. Game is set for jobs about a game, they are cancelled when the turn passes
+#[derive(Deserialize, Serialize, sqlx::FromRow, Debug)]
+pub struct Job {
+    pub id: u64,
+    pub kind: JobKind,
+    pub game_id: Option<u32>,
+    pub payload: String,
+    pub status: JobStatus,
+    pub attempts: u8,
+    pub run_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+// The query parameters for listing jobs
+#[derive(Deserialize, Debug)]
+pub struct JobFilter {
+    pub status: Option<JobStatus>,
+    pub kind: Option<JobKind>,
+    pub game_id: Option<u32>,
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
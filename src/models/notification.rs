@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+// The game events users with notify get mail about. Stored as a MySQL ENUM
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Event {
+    YourTurn,
+    PlayerJoined,
+    GameOver,
+    GameAborted,
+}
+
+// A queued notification together with the recipient. Detail holds the players that joined
+#[derive(sqlx::FromRow, Debug)]
+pub struct Notification {
+    pub user_name: String,
+    pub game_id: u32,
+    pub event: Event,
+    pub detail: String,
+    pub queued: DateTime<Utc>,
+    pub display_name: String,
+    pub email_address: String,
+    pub locale: String,
+    pub notify: bool,
+}
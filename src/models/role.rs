@@ -0,0 +1,63 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+// What a user is allowed to do besides playing. Roles are sets of permissions (role_permission table), users get
+// roles through the user_role table. Admins have every permission. Stored as a MySQL ENUM
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Permission {
+    SetMotd,                // change the message of the day
+    ManageGames,            // abort any game, add the server to any game
+    ManageUsers,            // create, show and update users and give them roles
+    ManageAdmins,           // create admins or change the admin flag of a user
+    ManageRules,            // change the rules for new games
+    ManageServer,           // shutdown or kill the server
+    ManageTournaments,      // organize tournaments
+    UnlimitedGames,         // not limited by the maximum amount of active games
+}
+
+impl Permission {
+    pub const ALL: [Permission; 8] = [
+        Permission::SetMotd,
+        Permission::ManageGames,
+        Permission::ManageUsers,
+        Permission::ManageAdmins,
+        Permission::ManageRules,
+        Permission::ManageServer,
+        Permission::ManageTournaments,
+        Permission::UnlimitedGames,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::SetMotd => "set_motd",
+            Permission::ManageGames => "manage_games",
+            Permission::ManageUsers => "manage_users",
+            Permission::ManageAdmins => "manage_admins",
+            Permission::ManageRules => "manage_rules",
+            Permission::ManageServer => "manage_server",
+            Permission::ManageTournaments => "manage_tournaments",
+            Permission::UnlimitedGames => "unlimited_games",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// A role with its permissions
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Role {
+    pub name: String,
+    pub permissions: Vec<Permission>,
+}
+
+// The struct used for receiving the roles of a user as json
+#[derive(Deserialize, Serialize, Debug)]
+pub struct UserRoles {
+    pub roles: Vec<String>,
+}
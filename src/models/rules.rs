@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+use rand::{SeedableRng, rngs::StdRng};
+use crate::models::ship::{ShipClass, random_fleet};
+
+// The rules of the game. The server rules are stored as json in server.rules, every game keeps a copy of the
+// server rules at the moment it was created in game.rules so changes don't affect games in progress.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Rules {
+    pub min_board_size: u8,
+    pub max_board_size: u8,
+    pub min_players: u8,
+    pub max_players: u8,
+    pub fleet: Vec<ShipClass>,          // every ship class in the fleet is placed once
+    pub max_active_games: u8,           // per user
+    pub salvo: bool,                    // one shot per ship still afloat each turn
+    pub adjacency_allowed: bool,        // ships may touch each other (also diagonally)
+    pub turn_timeout: Option<u32>,      // seconds
+}
+
+impl Rules {
+    // The rules used when none are stored in the server table. The fleet is the complete ship_class table
+    pub fn default_with_fleet(fleet: Vec<ShipClass>) -> Self {
+        Rules {
+            min_board_size: 8,
+            max_board_size: 16,
+            min_players: 2,
+            max_players: 4,
+            fleet,
+            max_active_games: 3,
+            salvo: false,
+            adjacency_allowed: true,
+            turn_timeout: None,
+        }
+    }
+
+    // Check if the rules are playable. Returns the reason when they are not
+    pub fn validate(&self) -> Result<(), String> {
+        if self.fleet.is_empty() {
+            return Err("The fleet needs at least one ship".to_string());
+        }
+        if self.fleet.iter().enumerate().any(|(i, class)| self.fleet[..i].iter().any(|other| other.name == class.name)) {
+            return Err("Every ship class can only be in the fleet once".to_string());
+        }
+        // A ship's damage is a bitmap in a TINYINT
+        if self.fleet.iter().any(|class| class.size < 2 || class.size > 8) {
+            return Err("Ships must have a size between 2 and 8".to_string());
+        }
+        let largest = self.fleet.iter().map(|class| class.size).max().unwrap_or(0);
+        // The shots_map of a board is a VARBINARY(32), which holds 16x16 cells
+        if self.min_board_size < largest || self.min_board_size > self.max_board_size || self.max_board_size > 16 {
+            return Err(format!("Board size must be between {} and 16 with a minimum not above the maximum", largest));
+        }
+        // Without adjacency every ship also claims the cells right and below it, on a board one cell wider and higher
+        let cells: usize = self.fleet.iter().map(|class| class.size as usize).sum();
+        let side = self.min_board_size as usize;
+        let claimed: usize = self.fleet.iter().map(|class| 2 * (class.size as usize + 1)).sum();
+        if cells > side * side || (!self.adjacency_allowed && claimed > (side + 1) * (side + 1)) {
+            return Err("The fleet doesn't fit on the smallest board".to_string());
+        }
+        // The server places its fleet at random, so that must succeed on the smallest board too
+        if random_fleet(&self.fleet, self.min_board_size, self.adjacency_allowed, &mut StdRng::seed_from_u64(0)).is_none() {
+            return Err("The fleet can't be placed on the smallest board".to_string());
+        }
+        if self.min_players < 2 || self.min_players > self.max_players || self.max_players > 8 {
+            return Err("Players must be between 2 and 8 with a minimum not above the maximum".to_string());
+        }
+        if self.max_active_games == 0 {
+            return Err("Users must be allowed at least one active game".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Rules are stored as json in a BLOB. sqlx only accepts a plain type name for try_from, hence the alias
+pub type RulesBlob = Vec<u8>;
+
+impl TryFrom<RulesBlob> for Rules {
+    type Error = serde_json::Error;
+
+    fn try_from(blob: RulesBlob) -> Result<Self, Self::Error> {
+        serde_json::from_slice(&blob)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fleet(sizes: &[u8]) -> Vec<ShipClass> {
+        sizes.iter().enumerate().map(|(i, &size)| ShipClass { name: format!("ship{}", i), size }).collect()
+    }
+
+    #[test]
+    fn default_rules_are_valid() {
+        let mut rules = Rules::default_with_fleet(fleet(&[5, 4, 3, 3, 2]));
+        assert_eq!(rules.validate(), Ok(()));
+        rules.adjacency_allowed = false;
+        assert_eq!(rules.validate(), Ok(()));
+    }
+
+    #[test]
+    fn fleet_must_fit_on_the_smallest_board() {
+        let mut rules = Rules::default_with_fleet(fleet(&[8, 8, 8, 8, 8, 8, 8, 8, 2]));
+        assert!(rules.validate().is_err());
+
+        // 40 cells fit on 8x8, but not when ships may not touch
+        rules.fleet = fleet(&[8, 8, 8, 8, 8]);
+        assert_eq!(rules.validate(), Ok(()));
+        rules.adjacency_allowed = false;
+        assert!(rules.validate().is_err());
+    }
+}
@@ -1,7 +1,39 @@
 use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Local};
 
 #[derive(Deserialize, Serialize, sqlx::FromRow, Debug)]
 pub struct Server {
     pub name: String,
     pub motd: String,
 }
+
+// The status of the server, used for monitoring
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerStatus {
+    pub started: DateTime<Local>,
+    pub uptime: i64,                    // seconds
+    pub total_games: i64,
+    pub total_players: i64,
+    pub active_games: i64,
+    pub motd: String,
+}
+
+// One entry in a highscore table: a game won by the player. See doc/notes.md for the formula of the highscore
+#[derive(Deserialize, Serialize, sqlx::FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Highscore {
+    pub player_id: String,
+    pub high_score: u64,
+    pub datetime: DateTime<Local>,
+    pub game_id: u32,
+}
+
+// The query parameters of a highscore request. There is a table per board size and amount of players
+#[derive(Deserialize, Debug)]
+pub struct HighscoreFilter {
+    pub board_size: u8,
+    pub players: u8,
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use rand::{Rng, seq::SliceRandom};
+
+// A placed ship, joined with the size of its class (the size is not stored in the ship table itself)
+#[derive(Deserialize, Serialize, sqlx::FromRow, Debug)]
+pub struct Ship {
+    pub game_id: u32,
+    pub user_name: String,
+    pub name: String,
+    pub class: String,
+    pub position_x: u8,
+    pub position_y: u8,
+    pub direction: Direction,
+    pub damage: u8,
+    pub size: u8,
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow, Debug, Clone)]
+pub struct ShipClass {
+    pub name: String,
+    pub size: u8,
+}
+
+// Direction in which the ship extends from its position (the bow). Stored as a MySQL ENUM
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Direction {
+    North,
+    South,
+    East,
+    West,
+}
+
+// The struct used for receiving a ship placement as json
+#[derive(Deserialize, Serialize, Debug)]
+pub struct NewShip {
+    pub class: String,
+    pub x: u8,
+    pub y: u8,
+    pub direction: Direction,
+}
+
+impl Direction {
+    // Step on the board when moving one cell from the bow towards the stern
+    fn step(&self) -> (i16, i16) {
+        match self {
+            Direction::North => (0, -1),
+            Direction::South => (0, 1),
+            Direction::East => (1, 0),
+            Direction::West => (-1, 0),
+        }
+    }
+}
+
+// Calculate the cells covered by a ship of the given size. Cell i of the result corresponds with bit i
+// of the damage bitmap. Returns None when (part of) the ship would be outside a board of board_size.
+pub fn ship_cells(x: u8, y: u8, direction: Direction, size: u8, board_size: u8) -> Option<Vec<(u8, u8)>> {
+    let (dx, dy) = direction.step();
+    let mut cells = Vec::with_capacity(size as usize);
+    for i in 0..size as i16 {
+        let cx = x as i16 + dx * i;
+        let cy = y as i16 + dy * i;
+        if cx < 0 || cy < 0 || cx >= board_size as i16 || cy >= board_size as i16 {
+            return None;
+        }
+        cells.push((cx as u8, cy as u8));
+    }
+    Some(cells)
+}
+
+// Check if two sets of cells are next to each other, diagonal neighbours included
+pub fn touching(cells: &[(u8, u8)], other: &[(u8, u8)]) -> bool {
+    cells.iter().any(|(x, y)| other.iter().any(|(ox, oy)| x.abs_diff(*ox) <= 1 && y.abs_diff(*oy) <= 1))
+}
+
+// Place every ship class at a random position, biggest ships first. Gives up after a number of attempts. Used by
+// the server to place its fleet and by the rules to check that a fleet can be placed at all
+pub fn random_fleet(classes: &[ShipClass], board_size: u8, adjacency_allowed: bool, rng: &mut impl Rng) -> Option<Vec<NewShip>> {
+
+    let mut classes = classes.to_vec();
+    classes.sort_by_key(|class| std::cmp::Reverse(class.size));
+    let directions = [Direction::North, Direction::South, Direction::East, Direction::West];
+
+    'attempt: for _ in 0..100 {
+        let mut fleet = Vec::new();
+        let mut occupied: Vec<(u8, u8)> = Vec::new();
+        let mut placed_ships: Vec<Vec<(u8, u8)>> = Vec::new();
+
+        for class in &classes {
+            let placed = (0..1000).find_map(|_| {
+                let x = rng.gen_range(0..board_size);
+                let y = rng.gen_range(0..board_size);
+                let direction = *directions.choose(rng)?;
+                let cells = ship_cells(x, y, direction, class.size, board_size)?;
+                if cells.iter().any(|cell| occupied.contains(cell)) {
+                    return None;
+                }
+                if !adjacency_allowed && placed_ships.iter().any(|other| touching(&cells, other)) {
+                    return None;
+                }
+                Some((NewShip { class: class.name.clone(), x, y, direction }, cells))
+            });
+
+            match placed {
+                Some((ship, cells)) => {
+                    occupied.extend(cells.iter().copied());
+                    placed_ships.push(cells);
+                    fleet.push(ship);
+                }
+                None => continue 'attempt,
+            }
+        }
+        return Some(fleet);
+    }
+    None
+}
+
+impl Ship {
+    // The cells covered by this ship. A ship in the database has already been validated against the board
+    pub fn cells(&self) -> Vec<(u8, u8)> {
+        ship_cells(self.position_x, self.position_y, self.direction, self.size, u8::MAX).unwrap_or_default()
+    }
+
+    // Mark the cell as hit in the damage bitmap. Returns false when the ship doesn't cover the cell or the cell was
+    // already hit, so damage is only counted once
+    pub fn hit(&mut self, cell: (u8, u8)) -> bool {
+        match self.cells().iter().position(|covered| *covered == cell) {
+            Some(bit) => {
+                let new = self.damage & (1 << bit) == 0;
+                self.damage |= 1 << bit;
+                new
+            },
+            None => false,
+        }
+    }
+
+    // Every cell of the ship is hit
+    pub fn is_sunk(&self) -> bool {
+        self.damage as u16 == (1u16 << self.size) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn ships_extend_from_the_bow() {
+        assert_eq!(ship_cells(2, 3, Direction::East, 3, 8), Some(vec![(2, 3), (3, 3), (4, 3)]));
+        assert_eq!(ship_cells(2, 3, Direction::North, 3, 8), Some(vec![(2, 3), (2, 2), (2, 1)]));
+        assert_eq!(ship_cells(2, 3, Direction::West, 3, 8), Some(vec![(2, 3), (1, 3), (0, 3)]));
+        assert_eq!(ship_cells(2, 3, Direction::South, 3, 8), Some(vec![(2, 3), (2, 4), (2, 5)]));
+    }
+
+    #[test]
+    fn ships_stay_on_the_board() {
+        assert_eq!(ship_cells(1, 0, Direction::West, 3, 8), None);
+        assert_eq!(ship_cells(0, 1, Direction::North, 3, 8), None);
+        assert_eq!(ship_cells(6, 0, Direction::East, 3, 8), None);
+        assert_eq!(ship_cells(0, 6, Direction::South, 3, 8), None);
+        assert_eq!(ship_cells(8, 0, Direction::South, 2, 8), None);
+        assert!(ship_cells(5, 7, Direction::East, 3, 8).is_some());
+    }
+
+    fn unhit(size: u8) -> Ship {
+        Ship {
+            game_id: 1,
+            user_name: "alice".to_string(),
+            name: "Ship".to_string(),
+            class: "Ship".to_string(),
+            position_x: 0,
+            position_y: 0,
+            direction: Direction::East,
+            damage: 0,
+            size,
+        }
+    }
+
+    #[test]
+    fn hits_set_the_bit_of_the_cell() {
+        let mut ship = unhit(3);
+        assert!(ship.hit((1, 0)));
+        assert_eq!(ship.damage, 0b010);
+        assert!(!ship.hit((3, 0)));
+        assert!(!ship.hit((1, 1)));
+        assert_eq!(ship.damage, 0b010);
+    }
+
+    #[test]
+    fn repeated_hits_are_not_new() {
+        let mut ship = unhit(3);
+        assert!(ship.hit((1, 0)));
+        assert!(!ship.hit((1, 0)));
+        assert_eq!(ship.damage, 0b010);
+    }
+
+    #[test]
+    fn ship_sinks_when_every_cell_is_hit() {
+        let mut ship = unhit(3);
+        ship.hit((0, 0));
+        ship.hit((2, 0));
+        assert!(!ship.is_sunk());
+        ship.hit((1, 0));
+        assert!(ship.is_sunk());
+
+        // The largest ship uses every bit of the TINYINT
+        let mut ship = unhit(8);
+        (0..7).for_each(|x| { ship.hit((x, 0)); });
+        assert!(!ship.is_sunk());
+        ship.hit((7, 0));
+        assert_eq!(ship.damage, u8::MAX);
+        assert!(ship.is_sunk());
+    }
+
+    fn classes(sizes: &[u8]) -> Vec<ShipClass> {
+        sizes.iter().enumerate().map(|(i, &size)| ShipClass { name: format!("ship{}", i), size }).collect()
+    }
+
+    #[test]
+    fn random_fleets_are_placeable() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let fleet_classes = classes(&[5, 4, 3, 3, 2]);
+        for adjacency_allowed in [true, false] {
+            let fleet = random_fleet(&fleet_classes, 8, adjacency_allowed, &mut rng).unwrap();
+            assert_eq!(fleet.len(), 5);
+            let placed: Vec<Vec<(u8, u8)>> = fleet.iter()
+                .map(|ship| {
+                    let size = fleet_classes.iter().find(|class| class.name == ship.class).unwrap().size;
+                    ship_cells(ship.x, ship.y, ship.direction, size, 8).unwrap()
+                })
+                .collect();
+            for (i, cells) in placed.iter().enumerate() {
+                for other in &placed[..i] {
+                    assert!(!cells.iter().any(|cell| other.contains(cell)));
+                    assert!(adjacency_allowed || !touching(cells, other));
+                }
+            }
+        }
+        assert!(random_fleet(&classes(&[8, 8]), 4, true, &mut rng).is_none());
+    }
+
+    #[test]
+    fn touching_includes_diagonals() {
+        let ship = ship_cells(2, 2, Direction::East, 2, 8).unwrap();
+        assert!(touching(&ship, &[(4, 2)]));
+        assert!(touching(&ship, &[(4, 3)]));
+        assert!(touching(&ship, &[(1, 1)]));
+        assert!(!touching(&ship, &[(5, 2)]));
+        assert!(!touching(&ship, &[(2, 4)]));
+    }
+}
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize, sqlx::FromRow)]
+// The user record. It is never serialized, responses use one of the views below that list the fields they show
+#[derive(sqlx::FromRow)]
 pub struct User {
     pub name: String,
     pub password_hash: String,
@@ -9,8 +10,13 @@ pub struct User {
     pub admin: bool,
     pub active: bool,
     pub notify: bool,
-    pub verification: u32,
-    pub new_password_hash: String
+    pub token_generation: u32,
+    pub locale: String,
+}
+
+// Users that don't give a language get English mail
+fn default_locale() -> String {
+    "en".to_string()
 }
 
 // The struct used for receiving user data for creating a user record as json
@@ -23,6 +29,8 @@ pub struct NewUser {
     pub admin: bool,
     pub active: bool,
     pub notify: bool,
+    #[serde(default = "default_locale")]
+    pub locale: String,
 }
 
 // The struct used for receiving user data for creating a user record as json
@@ -33,6 +41,8 @@ pub struct SignUp {
     pub display_name: String,
     pub email_address: String,
     pub notify: bool,
+    #[serde(default = "default_locale")]
+    pub locale: String,
 }
 // The struct used for receiving user data for updating the user record as json
 #[derive(Deserialize, Serialize)]
@@ -44,6 +54,65 @@ pub struct UpdateUser {
     pub notify: bool,
 }
 
+// The view of a user for user managers as json
+#[derive(Serialize)]
+pub struct AdminUserView {
+    pub name: String,
+    pub display_name: String,
+    pub email_address: String,
+    pub admin: bool,
+    pub active: bool,
+    pub notify: bool,
+    pub locale: String,
+}
+
+impl From<User> for AdminUserView {
+    fn from(user: User) -> AdminUserView {
+        AdminUserView {
+            name: user.name,
+            display_name: user.display_name,
+            email_address: user.email_address,
+            admin: user.admin,
+            active: user.active,
+            notify: user.notify,
+            locale: user.locale,
+        }
+    }
+}
+
+// The view of the logged in user as json
+#[derive(Serialize)]
+pub struct Profile {
+    pub name: String,
+    pub display_name: String,
+    pub email_address: String,
+    pub admin: bool,
+    pub notify: bool,
+    pub locale: String,
+}
+
+impl From<User> for Profile {
+    fn from(user: User) -> Profile {
+        Profile {
+            name: user.name,
+            display_name: user.display_name,
+            email_address: user.email_address,
+            admin: user.admin,
+            notify: user.notify,
+            locale: user.locale,
+        }
+    }
+}
+
+// The struct used for receiving changes to the own profile as json. Missing fields are left as they are
+#[derive(Deserialize, Serialize)]
+pub struct UpdateProfile {
+    pub display_name: Option<String>,
+    pub email_address: Option<String>,
+    pub notify: Option<bool>,
+    pub locale: Option<String>,
+}
+
 // The struct used for receiving a the old and new password as json
 #[derive(Deserialize, Serialize)]
 pub struct ChangePassword {
@@ -51,6 +120,20 @@ pub struct ChangePassword {
     pub new_password: String,
 }
 
+// The struct used for requesting a password reset as json. user is the name or the email address
+#[derive(Deserialize, Serialize)]
+pub struct ForgotPassword {
+    pub user: String,
+    pub new_password: String,
+}
+
+// The struct used for confirming a password reset as json
+#[derive(Deserialize, Serialize)]
+pub struct ResetPassword {
+    pub user: String,
+    pub verification_number: u32,
+}
+
 // The struct used for receiving verification as json
 #[derive(Deserialize, Serialize)]
 pub struct Verification {
@@ -63,4 +146,11 @@ pub struct AuthResponse {
     pub access_token: String,
     pub token_type: String,
     pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+// The struct used for receiving a refresh token as json
+#[derive(Deserialize, Serialize)]
+pub struct RefreshToken {
+    pub refresh_token: String,
 }
\ No newline at end of file
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+// What a verification code confirms. A user can have one pending code per purpose. Stored as a MySQL ENUM
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Purpose {
+    Signup,
+    PasswordChange,
+    PasswordReset,
+    EmailChange,
+}
+
+// A pending verification. The payload is what gets applied when the code is verified: the new password hash or
+// the new email address
+#[derive(sqlx::FromRow, Debug)]
+pub struct VerificationCode {
+    pub user_name: String,
+    pub purpose: Purpose,
+    pub code: u32,
+    pub payload: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub attempts: u8,
+}
@@ -0,0 +1,235 @@
+use sqlx::{MySql, MySqlPool, Transaction};
+use log::{error, info};
+use chrono::{Duration, Utc};
+use crate::models::{notification::*, board::BoardStatus};
+use crate::mail::template::Template;
+use crate::job::{self, Task};
+use crate::errors::CustomError;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Mail about game events for users with notify. The state machine queues a notification in the notification table
+// within the transaction of the transition, a background task turns them into mail jobs. There is one row per user,
+// game and event, so events that follow each other quickly are batched into one mail:
+// - a notification is sent when it has been queued for BATCH_DELAY, players that join in the meantime are added
+// - your turn is sent at most once per TURN_THROTTLE per game, and only when it is still the turn of the user
+// The job workers send the mail and retry it when the mail server fails.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// How often the queue is processed, in seconds
+const INTERVAL: u64 = 30;
+
+// How long a notification waits for more events, in seconds
+const BATCH_DELAY: i64 = 60;
+
+// Minimum time between two your turn mails for the same game, in seconds
+const TURN_THROTTLE: i64 = 15 * 60;
+
+// How long sent notifications are kept for the throttle, in seconds
+const RETENTION: i64 = 24 * 60 * 60;
+
+// Queue a notification for one user. Users without notify are skipped
+pub async fn queue_for_user(tx: &mut Transaction<'_, MySql>, user_name: &str, game_id: u32, event: Event) -> Result<(), CustomError> {
+
+    // The assignments are done from left to right, queued must see the old pending
+    let sql = "INSERT INTO notification (user_name, game_id, event) SELECT name, ?, ? FROM user WHERE name = ? AND notify = true \
+               ON DUPLICATE KEY UPDATE queued = IF(pending, queued, CURRENT_TIMESTAMP), pending = true";
+    sqlx::query(sql)
+        .bind(game_id)
+        .bind(event)
+        .bind(user_name)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error queueing {:?} for {}: {:?}", event, user_name, err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(())
+}
+
+// Queue a notification for every player of the game except one, the detail is added to a pending notification
+pub async fn queue_for_players(tx: &mut Transaction<'_, MySql>, game_id: u32, event: Event, except: &str, detail: &str) -> Result<(), CustomError> {
+
+    // The assignments are done from left to right, detail and queued must see the old pending
+    let sql = "INSERT INTO notification (user_name, game_id, event, detail) \
+                   SELECT board.user_name, board.game_id, ?, ? FROM board JOIN user ON user.name = board.user_name \
+                   WHERE board.game_id = ? AND board.user_name != ? AND user.notify = true \
+               ON DUPLICATE KEY UPDATE detail = IF(pending AND detail != '', LEFT(CONCAT(detail, ', ', VALUES(detail)), 255), VALUES(detail)), \
+                                       queued = IF(pending, queued, CURRENT_TIMESTAMP), pending = true";
+    sqlx::query(sql)
+        .bind(event)
+        .bind(detail)
+        .bind(game_id)
+        .bind(except)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error queueing {:?} for game {}: {:?}", event, game_id, err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(())
+}
+
+// The background task, started from main
+pub async fn run(pool: MySqlPool) {
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(INTERVAL));
+    loop {
+        interval.tick().await;
+        if let Err(err) = process(&pool).await {
+            error!("Error processing notifications: {:?}", err);
+        }
+    }
+}
+
+// Queue mail for the notifications that are due
+async fn process(pool: &MySqlPool) -> Result<(), CustomError> {
+
+    let now = Utc::now();
+
+    let sql = "SELECT notification.user_name, notification.game_id, notification.event, notification.detail, notification.queued, \
+                      user.display_name, user.email_address, user.locale, user.notify \
+               FROM notification JOIN user ON user.name = notification.user_name \
+               WHERE notification.pending AND notification.queued <= ? \
+                 AND (notification.event != ? OR notification.sent IS NULL OR notification.sent <= ?)";
+    let due: Vec<Notification> = sqlx::query_as(sql)
+        .bind(now - Duration::seconds(BATCH_DELAY))
+        .bind(Event::YourTurn)
+        .bind(now - Duration::seconds(TURN_THROTTLE))
+        .fetch_all(pool)
+        .await
+        .map_err(|err| {
+            error!("Error fetching notifications: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // A notification that fails stays pending for the next round, the others are handled anyway
+    for notification in due {
+        if let Err(err) = handle(pool, &notification).await {
+            error!("Error handling {:?} of game {} for {}: {:?}",
+                   notification.event, notification.game_id, notification.user_name, err);
+        }
+    }
+
+    let sql = "DELETE FROM notification WHERE NOT pending AND (sent IS NULL OR sent <= ?)";
+    sqlx::query(sql)
+        .bind(now - Duration::seconds(RETENTION))
+        .execute(pool)
+        .await
+        .map_err(|err| {
+            error!("Error removing old notifications: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(())
+}
+
+// Queue the mail and mark the notification as done, both or neither
+async fn handle(pool: &MySqlPool, notification: &Notification) -> Result<(), CustomError> {
+
+    let wanted = notification.notify && still_relevant(pool, notification).await?;
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error handling notification: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    if wanted {
+        send(&mut tx, notification).await?;
+    }
+    done(&mut tx, notification, wanted).await?;
+
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error handling notification: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    if wanted {
+        info!("{:?} of game {} queued at {} mailed to {}",
+              notification.event, notification.game_id, notification.queued, notification.user_name);
+    }
+    Ok(())
+}
+
+// Your turn is only sent while it is still the turn of the user
+async fn still_relevant(pool: &MySqlPool, notification: &Notification) -> Result<bool, CustomError> {
+
+    if notification.event != Event::YourTurn {
+        return Ok(true);
+    }
+
+    let sql = "SELECT COUNT(*) FROM board WHERE game_id = ? AND user_name = ? AND status = ?";
+    let shooting: i64 = sqlx::query_scalar(sql)
+        .bind(notification.game_id)
+        .bind(&notification.user_name)
+        .bind(BoardStatus::Shooting)
+        .fetch_one(pool)
+        .await
+        .map_err(|err| {
+            error!("Error checking turn: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(shooting > 0)
+}
+
+async fn send(tx: &mut Transaction<'_, MySql>, notification: &Notification) -> Result<(), CustomError> {
+
+    let game_id = notification.game_id.to_string();
+    let (display_name, email_address, locale) = (&notification.display_name, &notification.email_address, &notification.locale);
+
+    let task = match notification.event {
+        Event::YourTurn => {
+            Task::mail(display_name, email_address, locale, Template::YourTurn, &[("game_id", &game_id)])
+        },
+        Event::PlayerJoined => {
+            Task::mail(display_name, email_address, locale, Template::PlayerJoined,
+                       &[("game_id", &game_id), ("players", &notification.detail)])
+        },
+        Event::GameOver => {
+            let sql = "SELECT user_name, CAST(score AS UNSIGNED) FROM board WHERE game_id = ? AND status = ?";
+            let (winner, score): (String, u64) = sqlx::query_as(sql)
+                .bind(notification.game_id)
+                .bind(BoardStatus::Won)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|err| {
+                    error!("Error fetching the winner of game {}: {:?}", notification.game_id, err);
+                    CustomError::InternalServerError
+                })?;
+            Task::mail(display_name, email_address, locale, Template::GameOver,
+                       &[("game_id", &game_id), ("winner", &winner), ("score", &score.to_string())])
+        },
+        Event::GameAborted => {
+            Task::mail(display_name, email_address, locale, Template::GameAborted, &[("game_id", &game_id)])
+        },
+    };
+    job::enqueue(&mut *tx, &task, Utc::now()).await
+}
+
+// The notification is handled, only a mail that was queued counts for the throttle. Players that joined after it was
+// fetched keep it pending
+async fn done(tx: &mut Transaction<'_, MySql>, notification: &Notification, sent: bool) -> Result<(), CustomError> {
+
+    let sql = "UPDATE notification SET pending = false, sent = IF(?, ?, sent) WHERE user_name = ? AND game_id = ? AND event = ? AND detail = ?";
+    sqlx::query(sql)
+        .bind(sent)
+        .bind(Utc::now())
+        .bind(&notification.user_name)
+        .bind(notification.game_id)
+        .bind(notification.event)
+        .bind(&notification.detail)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error marking notification as sent: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(())
+}
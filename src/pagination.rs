@@ -0,0 +1,59 @@
+use axum::http::header::{HeaderMap, HeaderValue};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Pagination of lists. The page starts at 1 and per_page defaults to 10 with a maximum of 100. The offset is
+// computed in u64 so a huge page number can't overflow, MySQL simply returns an empty page. The Total-Count header
+// holds the amount of matching rows and Next-Page tells if there is more.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub struct Page {
+    pub limit: u32,
+    pub offset: u64,
+}
+
+impl Page {
+    pub fn new(page: Option<u32>, per_page: Option<u32>) -> Page {
+        let limit = per_page.unwrap_or(10).clamp(1, 100);
+        let page = page.unwrap_or(1).max(1);
+        Page { limit, offset: (page as u64 - 1) * limit as u64 }
+    }
+
+    // The Total-Count and Next-Page headers of the response
+    pub fn headers(&self, total: i64) -> HeaderMap {
+        let more = ((self.offset + self.limit as u64) as i64) < total;
+        let mut headers = HeaderMap::new();
+        headers.insert("Total-Count", HeaderValue::from(total));
+        headers.insert("Next-Page", HeaderValue::from_static(if more { "true" } else { "false" }));
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_start_at_one() {
+        let page = Page::new(None, None);
+        assert_eq!((page.limit, page.offset), (10, 0));
+        let page = Page::new(Some(0), Some(0));
+        assert_eq!((page.limit, page.offset), (1, 0));
+        let page = Page::new(Some(3), Some(500));
+        assert_eq!((page.limit, page.offset), (100, 200));
+    }
+
+    #[test]
+    fn huge_pages_do_not_overflow() {
+        let page = Page::new(Some(u32::MAX), Some(100));
+        assert_eq!(page.offset, (u32::MAX as u64 - 1) * 100);
+        assert_eq!(page.headers(1000)["Next-Page"], "false");
+    }
+
+    #[test]
+    fn next_page_when_there_is_more() {
+        let page = Page::new(Some(2), Some(10));
+        assert_eq!(page.headers(21)["Next-Page"], "true");
+        assert_eq!(page.headers(20)["Next-Page"], "false");
+        assert_eq!(page.headers(20)["Total-Count"], "20");
+    }
+}
@@ -0,0 +1,262 @@
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use log::error;
+use serde::Serialize;
+use std::{collections::HashSet, env, sync::Arc};
+use crate::errors::CustomError;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Password hashing. New hashes are Argon2id PHC strings with the parameters from the environment. Hashes made with
+// bcrypt ($2b$) by earlier versions can still be verified, they are replaced by an Argon2id hash at the next login.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Defaults for the Argon2id parameters: memory in KiB, iterations and lanes
+const DEFAULT_MEMORY_COST: u32 = 19 * 1024;
+const DEFAULT_TIME_COST: u32 = 2;
+const DEFAULT_PARALLELISM: u32 = 1;
+
+#[derive(Clone)]
+pub struct Hasher {
+    params: Params,
+}
+
+impl Hasher {
+
+    // The parameters are read from $PASSWORD_MEMORY_COST, $PASSWORD_TIME_COST and $PASSWORD_PARALLELISM
+    pub fn from_env() -> Hasher {
+        let memory_cost = env_or("PASSWORD_MEMORY_COST", DEFAULT_MEMORY_COST);
+        let time_cost = env_or("PASSWORD_TIME_COST", DEFAULT_TIME_COST);
+        let parallelism = env_or("PASSWORD_PARALLELISM", DEFAULT_PARALLELISM);
+        Hasher {
+            params: Params::new(memory_cost, time_cost, parallelism, None).expect("Invalid password hashing parameters"),
+        }
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    // Hash a password with a random salt
+    pub fn hash(&self, password: &str) -> Result<String, CustomError> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| {
+                error!("Unexpected error hashing password {:?}", err);
+                CustomError::InternalServerError
+            })
+    }
+
+    // Check a password against a bcrypt or Argon2id hash. An empty or unknown hash never matches
+    pub fn verify(&self, password: &str, hash: &str) -> bool {
+        if hash.starts_with("$2") {
+            return pwhash::bcrypt::verify(password, hash);
+        }
+        match PasswordHash::new(hash) {
+            Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    // A hash needs to be replaced when it isn't Argon2id or has other parameters than the configured ones
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let parsed = match PasswordHash::new(hash) {
+            Ok(parsed) if parsed.algorithm == argon2::ARGON2ID_IDENT => parsed,
+            _ => return true,
+        };
+        match Params::try_from(&parsed) {
+            Ok(params) => params.m_cost() != self.params.m_cost()
+                || params.t_cost() != self.params.t_cost()
+                || params.p_cost() != self.params.p_cost(),
+            Err(_) => true,
+        }
+    }
+}
+
+fn env_or(name: &str, default: u32) -> u32 {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("${} is not numeric", name)),
+        Err(_) => default,
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Password policy for signups, password changes and resets. Users created by an admin don't have to comply.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Passwords that are always refused
+const BANNED_PASSWORDS: &str = include_str!("banned_passwords.txt");
+
+// Defaults for the policy: length in characters and the number of character classes (lowercase, uppercase, digits
+// and others) that must be used
+const DEFAULT_MIN_LENGTH: u32 = 8;
+const DEFAULT_MAX_LENGTH: u32 = 64;
+const DEFAULT_CHARACTER_CLASSES: u32 = 3;
+
+// A rule the password doesn't comply with, returned to the client
+#[derive(Serialize, Debug)]
+pub struct PolicyViolation {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+#[derive(Clone)]
+pub struct Policy {
+    min_length: usize,
+    max_length: usize,
+    character_classes: usize,
+    banned: Arc<HashSet<String>>,
+}
+
+impl Policy {
+
+    // The policy is read from $PASSWORD_MIN_LENGTH, $PASSWORD_MAX_LENGTH and $PASSWORD_CHARACTER_CLASSES
+    pub fn from_env() -> Policy {
+        let banned = BANNED_PASSWORDS
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect();
+        Policy {
+            min_length: env_or("PASSWORD_MIN_LENGTH", DEFAULT_MIN_LENGTH) as usize,
+            max_length: env_or("PASSWORD_MAX_LENGTH", DEFAULT_MAX_LENGTH) as usize,
+            character_classes: env_or("PASSWORD_CHARACTER_CLASSES", DEFAULT_CHARACTER_CLASSES).min(4) as usize,
+            banned: Arc::new(banned),
+        }
+    }
+
+    // Check the password against every rule. The names are those of the user, empty names are skipped
+    pub fn check(&self, password: &str, names: &[&str]) -> Result<(), CustomError> {
+
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+        let lowercase = password.to_lowercase();
+
+        if length < self.min_length {
+            violations.push(PolicyViolation {
+                rule: "min_length",
+                message: format!("The password needs at least {} characters", self.min_length),
+            });
+        }
+        if length > self.max_length {
+            violations.push(PolicyViolation {
+                rule: "max_length",
+                message: format!("The password can have at most {} characters", self.max_length),
+            });
+        }
+
+        let classes = [
+            password.chars().any(char::is_lowercase),
+            password.chars().any(char::is_uppercase),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|&&used| used).count() < self.character_classes {
+            violations.push(PolicyViolation {
+                rule: "character_classes",
+                message: format!("The password needs {} of: lowercase letters, uppercase letters, digits and other characters",
+                                 self.character_classes),
+            });
+        }
+
+        if self.banned.contains(&lowercase) {
+            violations.push(PolicyViolation {
+                rule: "banned",
+                message: "The password is too common".to_string(),
+            });
+        }
+
+        if names.iter().any(|name| !name.trim().is_empty() && lowercase.contains(&name.trim().to_lowercase())) {
+            violations.push(PolicyViolation {
+                rule: "contains_name",
+                message: "The password can not contain your username or display name".to_string(),
+            });
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(CustomError::WeakPassword(violations))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The bcrypt hash seeded for the admin in doc/schema.sql
+    const SEEDED_HASH: &str = "$2b$10$lVkR4Xbo0BiOVts1QOw0w.dqTUSBOepeZbSxMNggY9KlOg1T5MTU6";
+
+    // Cheap parameters, the tests don't need a strong hash
+    fn hasher() -> Hasher {
+        Hasher { params: Params::new(1024, 1, 1, None).unwrap() }
+    }
+
+    #[test]
+    fn argon2id_hashes_verify() {
+        let hasher = hasher();
+        let hash = hasher.hash("Sunk-my-Battleship").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(hasher.verify("Sunk-my-Battleship", &hash));
+        assert!(!hasher.verify("sunk-my-battleship", &hash));
+        assert!(!hasher.needs_rehash(&hash));
+
+        let stronger = Hasher { params: Params::new(2048, 1, 1, None).unwrap() };
+        assert!(stronger.verify("Sunk-my-Battleship", &hash));
+        assert!(stronger.needs_rehash(&hash));
+    }
+
+    #[test]
+    fn bcrypt_hashes_verify_and_need_a_rehash() {
+        let hasher = hasher();
+        assert!(!hasher.verify("wrong password", SEEDED_HASH));
+        assert!(hasher.needs_rehash(SEEDED_HASH));
+
+        let hash = pwhash::bcrypt::hash("Sunk-my-Battleship").unwrap();
+        assert!(hash.starts_with("$2"));
+        assert!(hasher.verify("Sunk-my-Battleship", &hash));
+        assert!(!hasher.verify("Sunk-my-Cruiser", &hash));
+    }
+
+    #[test]
+    fn empty_and_unknown_hashes_never_match() {
+        let hasher = hasher();
+        assert!(!hasher.verify("", ""));
+        assert!(!hasher.verify("password", "password"));
+        assert!(hasher.needs_rehash(""));
+    }
+
+    fn policy() -> Policy {
+        Policy {
+            min_length: 8,
+            max_length: 16,
+            character_classes: 3,
+            banned: Arc::new(["passw0rd!".to_string()].into_iter().collect()),
+        }
+    }
+
+    fn violations(result: Result<(), CustomError>) -> Vec<&'static str> {
+        match result {
+            Ok(()) => Vec::new(),
+            Err(CustomError::WeakPassword(violations)) => violations.iter().map(|violation| violation.rule).collect(),
+            Err(err) => panic!("Unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn policy_checks_every_rule() {
+        let policy = policy();
+        assert_eq!(violations(policy.check("Sunk-my-Ship7", &["alice", "Alice A."])), Vec::<&str>::new());
+        assert_eq!(violations(policy.check("Ab1!", &[])), vec!["min_length"]);
+        assert_eq!(violations(policy.check("Abcdefgh1!abcdefgh", &[])), vec!["max_length"]);
+        assert_eq!(violations(policy.check("abcdefgh1", &[])), vec!["character_classes"]);
+        assert_eq!(violations(policy.check("PassW0rd!", &[])), vec!["banned"]);
+        assert_eq!(violations(policy.check("xxALICE-1", &["alice", ""])), vec!["contains_name"]);
+        assert_eq!(violations(policy.check("abc", &["abc"])), vec!["min_length", "character_classes", "contains_name"]);
+    }
+}
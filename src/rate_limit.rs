@@ -0,0 +1,194 @@
+use axum::{
+    Extension,
+    extract::{ConnectInfo, State, TypedHeader},
+    headers::{Authorization, authorization::{Basic, Bearer}},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::MySqlPool;
+use log::{error, info};
+use chrono::{DateTime, Duration, Utc};
+use std::{collections::HashMap, hash::Hash, net::{IpAddr, SocketAddr}, sync::Mutex, time::Instant};
+use crate::errors::CustomError;
+use crate::auth::{self, AuthUser};
+use crate::AppState;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Rate limiting for the routes where passwords and verification codes can be guessed. Every client address and
+// every username has a token bucket in memory, a request takes a token and is refused when the bucket is empty.
+// Failed attempts for a username are counted in the lockout table, too many of them lock the account for a while.
+// The lockout is kept in the DB so it survives a restart.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Requests a client address can do at once, and the number of requests per second it gets back
+const IP_BURST: f64 = 20.0;
+const IP_REFILL: f64 = 1.0 / 6.0;
+
+// Requests for one username at once, and the number of requests per second it gets back
+const USER_BURST: f64 = 5.0;
+const USER_REFILL: f64 = 1.0 / 60.0;
+
+// Failed attempts after which a username is locked, and for how long
+const MAX_FAILURES: u8 = 10;
+const LOCKOUT_DURATION: i64 = 15 * 60;
+
+// Above this many buckets the full ones are removed, they behave the same as a new one
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// The token buckets, per client address and per username
+pub struct RateLimiter {
+    ips: Mutex<HashMap<IpAddr, Bucket>>,
+    users: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            ips: Mutex::new(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+// Take a token from the bucket of the key. When it is empty the seconds until the next token are returned
+fn take<K: Eq + Hash>(buckets: &Mutex<HashMap<K, Bucket>>, key: K, burst: f64, refill: f64) -> Result<(), u64> {
+
+    let mut buckets = buckets.lock().unwrap();
+    let now = Instant::now();
+
+    if buckets.len() > MAX_BUCKETS {
+        buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * refill < burst);
+    }
+
+    let bucket = buckets.entry(key).or_insert(Bucket { tokens: burst, updated: now });
+    bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * refill).min(burst);
+    bucket.updated = now;
+
+    if bucket.tokens < 1.0 {
+        return Err(((1.0 - bucket.tokens) / refill).ceil() as u64);
+    }
+    bucket.tokens -= 1.0;
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// The middleware. The username comes from basic authentication or from the bearer token, never from the path or
+// the body. /signup, /password/forgot and /password/reset have neither, they only get the address bucket and no
+// lockout. A refused request gets TooManyAttempts with the seconds to wait.
+// After the handler a wrong password or code counts as a failure for the username, a success clears the failures.
+// A missing or invalid token is refused before that, it is no guess at a code.
+///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub async fn limit<B>(  State(state): State<AppState>,
+                        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                        Extension(pool): Extension<MySqlPool>,
+                        basic: Option<TypedHeader<Authorization<Basic>>>,
+                        bearer: Option<TypedHeader<Authorization<Bearer>>>,
+                        request: Request<B>,
+                        next: Next<B>,
+                        ) -> Response {
+
+    if let Err(retry_after) = take(&state.limiter.ips, addr.ip(), IP_BURST, IP_REFILL) {
+        info!("Rate limit reached for {}", addr.ip());
+        return CustomError::TooManyAttempts(retry_after).into_response();
+    }
+
+    let user_name = match (basic, bearer) {
+        (Some(TypedHeader(basic)), _) => Some(basic.username().to_string()),
+        (None, Some(TypedHeader(bearer))) => match auth::authenticate(&state, &pool, bearer.token()).await {
+            Ok(AuthUser(user, _)) => Some(user.name),
+            Err(err) => return err.into_response(),
+        },
+        (None, None) => None,
+    };
+
+    let user_name = match user_name {
+        Some(user_name) => user_name,
+        None => return next.run(request).await,
+    };
+
+    if let Err(retry_after) = take(&state.limiter.users, user_name.clone(), USER_BURST, USER_REFILL) {
+        info!("Rate limit reached for user {}", user_name);
+        return CustomError::TooManyAttempts(retry_after).into_response();
+    }
+
+    match locked_until(&pool, &user_name).await {
+        Ok(Some(until)) if until > Utc::now() => {
+            info!("User {} is locked until {}", user_name, until);
+            return CustomError::TooManyAttempts((until - Utc::now()).num_seconds().max(1) as u64).into_response();
+        },
+        Ok(_) => {},
+        Err(err) => return err.into_response(),
+    }
+
+    let response = next.run(request).await;
+
+    // A failure to keep count is logged, the response of the handler stands
+    let _ = match response.status() {
+        StatusCode::UNAUTHORIZED | StatusCode::BAD_REQUEST => record_failure(&pool, &user_name).await,
+        status if status.is_success() => clear_failures(&pool, &user_name).await,
+        _ => Ok(()),
+    };
+
+    response
+}
+
+// When the user is locked out
+async fn locked_until(pool: &MySqlPool, user_name: &str) -> Result<Option<DateTime<Utc>>, CustomError> {
+
+    let sql = "SELECT locked_until FROM lockout WHERE user_name = ?";
+    let locked_until: Option<Option<DateTime<Utc>>> = sqlx::query_scalar(sql)
+        .bind(user_name)
+        .fetch_optional(pool)
+        .await
+        .map_err(|err| {
+            error!("Error looking up lockout: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(locked_until.flatten())
+}
+
+// Count a failed attempt, the user is locked when there are too many. Unknown usernames are not stored
+async fn record_failure(pool: &MySqlPool, user_name: &str) -> Result<(), CustomError> {
+
+    // The assignments are done from left to right, locked_until must see the old failures
+    let sql = "INSERT INTO lockout (user_name, failures) SELECT name, 1 FROM user WHERE name = ? \
+               ON DUPLICATE KEY UPDATE locked_until = IF(failures + 1 >= ?, ?, locked_until), \
+                                       failures = IF(failures + 1 >= ?, 0, failures + 1)";
+    sqlx::query(sql)
+        .bind(user_name)
+        .bind(MAX_FAILURES)
+        .bind(Utc::now() + Duration::seconds(LOCKOUT_DURATION))
+        .bind(MAX_FAILURES)
+        .execute(pool)
+        .await
+        .map_err(|err| {
+            error!("Error recording failed attempt: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(())
+}
+
+// Forget the failed attempts after a success
+async fn clear_failures(pool: &MySqlPool, user_name: &str) -> Result<(), CustomError> {
+
+    let sql = "DELETE FROM lockout WHERE user_name = ?";
+    sqlx::query(sql)
+        .bind(user_name)
+        .execute(pool)
+        .await
+        .map_err(|err| {
+            error!("Error clearing failed attempts: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use std::{env, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::Duration};
+use sqlx::MySqlPool;
+use tokio::{sync::Notify, time::Instant};
+use log::{error, info, warn};
+use crate::models::{game::GameStatus, event::GameEvent};
+use crate::hub::Hub;
+use crate::state_machine;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Stopping the server. A clean shutdown (POST /server/shutdown or SIGTERM) drains the server: new games are
+// refused, games in progress are played to the end and then axum stops after the open requests are handled.
+// Games that are still active after $DRAIN_TIMEOUT seconds (default an hour), like games where a player never
+// places the fleet, are aborted.
+// A dirty shutdown (POST /server/kill) aborts all active games and stops right away, also while draining.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// How often the active games are counted while draining
+const DRAIN_INTERVAL: Duration = Duration::from_secs(10);
+
+// How long games get to finish when $DRAIN_TIMEOUT is not set, in seconds
+const DEFAULT_DRAIN_TIMEOUT: u64 = 3600;
+
+#[derive(Default)]
+pub struct Shutdown {
+    draining: AtomicBool,
+    drain: Notify,
+    kill: Notify,
+}
+
+impl Shutdown {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Shutdown::default())
+    }
+
+    // New games are refused while draining
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.drain.notify_one();
+    }
+
+    pub fn kill(&self) {
+        self.kill.notify_one();
+    }
+}
+
+// The future passed to axum's graceful shutdown. Resolves when the server is drained or killed
+pub async fn signal(pool: MySqlPool, hub: Arc<Hub>, shutdown: Arc<Shutdown>) {
+
+    tokio::select! {
+        _ = shutdown.drain.notified() => info!("Shutdown requested, draining"),
+        _ = sigterm() => {
+            info!("SIGTERM received, draining");
+            shutdown.draining.store(true, Ordering::SeqCst);
+        },
+        _ = shutdown.kill.notified() => {
+            info!("Server killed");
+            return;
+        },
+    }
+
+    let timeout = match env::var("DRAIN_TIMEOUT") {
+        Ok(value) => value.parse().expect("$DRAIN_TIMEOUT is not numeric"),
+        Err(_) => DEFAULT_DRAIN_TIMEOUT,
+    };
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+
+    loop {
+        if Instant::now() >= deadline {
+            warn!("Drain timeout passed, aborting the remaining games");
+            abort_remaining(&pool, &hub).await;
+            break;
+        }
+
+        let sql = "SELECT COUNT(*) FROM game WHERE status = ?";
+        match sqlx::query_as::<_, (i64,)>(sql)
+            .bind(GameStatus::Active)
+            .fetch_one(&pool)
+            .await {
+                Ok((0,)) => break,
+                Ok((active,)) => info!("Draining, waiting for {} active games", active),
+                Err(err) => error!("Error counting active games: {:?}", err),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline.min(Instant::now() + DRAIN_INTERVAL)) => {},
+            _ = shutdown.kill.notified() => {
+                info!("Server killed while draining");
+                return;
+            },
+        }
+    }
+    info!("All games finished, stopping");
+}
+
+// Abort the games that didn't finish in time. The server stops anyway, a failure is only logged
+async fn abort_remaining(pool: &MySqlPool, hub: &Hub) {
+
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            error!("Error aborting remaining games: {:?}", err);
+            return;
+        }
+    };
+    let aborted = match state_machine::abort_all_games(&mut tx).await {
+        Ok(aborted) => aborted,
+        Err(_) => return,
+    };
+    match tx.commit().await {
+        Ok(()) => for game_id in aborted {
+            hub.publish(game_id, &GameEvent::GameFinished { status: GameStatus::Aborted, winner: None });
+        },
+        Err(err) => error!("Error aborting remaining games: {:?}", err),
+    }
+}
+
+#[cfg(unix)]
+async fn sigterm() {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(mut signal) => { signal.recv().await; },
+        Err(err) => {
+            error!("Unable to listen for SIGTERM: {:?}", err);
+            std::future::pending::<()>().await
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn sigterm() {
+    std::future::pending::<()>().await
+}
@@ -0,0 +1,410 @@
+use sqlx::{MySql, Transaction};
+use log::{error, info};
+use rand::seq::SliceRandom;
+use bit_vec::BitVec;
+use chrono::{Duration, Utc};
+use crate::models::{game::*, board::*, ship::Ship, notification::Event};
+use crate::job::{self, Task};
+use crate::{ai::SERVER_USER, notification};
+use crate::errors::CustomError;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// The game state machine. All status changes of games and boards go through here, see doc/notes.md for the rules.
+// Every function works within the transaction of the caller. The game row is locked first so all transitions of
+// a game are serialized, and every status update is conditional on the expected current status. When the status
+// changed underneath us the update fails with InvalidTransition and the caller's transaction is rolled back.
+// Notifications and the jobs for the next turn (the move of the server or the turn timeout) are queued in the same
+// transaction.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// The player whose turn it is after a transition
+#[derive(Debug)]
+pub struct Shooter {
+    pub player_id: u8,
+    pub user_name: String,
+}
+
+// Fetch and lock the game. Controllers that change a game must call this before touching its boards.
+pub async fn lock_game(tx: &mut Transaction<'_, MySql>, game_id: u32) -> Result<Game, CustomError> {
+
+    let sql = "SELECT * FROM game WHERE id = ? FOR UPDATE";
+    sqlx::query_as(sql)
+        .bind(game_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error fetching game {}: {:?}", game_id, err);
+            CustomError::InvalidGame
+        })
+}
+
+// Fetch and lock all boards of a game, ordered by player_id
+pub async fn lock_boards(tx: &mut Transaction<'_, MySql>, game_id: u32) -> Result<Vec<Board>, CustomError> {
+
+    let sql = "SELECT * FROM board WHERE game_id = ? ORDER BY player_id FOR UPDATE";
+    sqlx::query_as(sql)
+        .bind(game_id)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error fetching boards of game {}: {:?}", game_id, err);
+            CustomError::InternalServerError
+        })
+}
+
+// Add a player to a game. The new board gets the next player_id and starts placing
+pub async fn add_player(tx: &mut Transaction<'_, MySql>, game: &Game, user_name: &str) -> Result<u8, CustomError> {
+
+    if game.status != GameStatus::Active {
+        return Err(CustomError::GameNotActive);
+    }
+
+    let boards = lock_boards(tx, game.id).await?;
+
+    if boards.iter().any(|board| board.user_name == user_name) {
+        error!("User {} is already a player in game {}", user_name, game.id);
+        return Err(CustomError::InvalidGame);
+    }
+
+    if boards.len() >= game.amount_of_players as usize {
+        return Err(CustomError::GameFull);
+    }
+
+    let shots_map = BitVec::from_elem((game.board_size as usize) * (game.board_size as usize), false).to_bytes();
+    let player_id = boards.len() as u8 + 1;
+
+    let sql = "INSERT INTO board (game_id, user_name, player_id, status, shots_map) VALUES (?, ?, ?, ?, ?)";
+    sqlx::query(sql)
+        .bind(game.id)
+        .bind(user_name)
+        .bind(player_id)
+        .bind(BoardStatus::Placing)
+        .bind(shots_map)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error adding player {} to game {}: {:?}", user_name, game.id, err);
+            CustomError::InternalServerError
+        })?;
+
+    notification::queue_for_players(tx, game.id, Event::PlayerJoined, user_name, user_name).await?;
+
+    Ok(player_id)
+}
+
+// A player placed his complete fleet. When all players are done placing a random player starts shooting,
+// this player is returned.
+pub async fn placing_done(tx: &mut Transaction<'_, MySql>, game: &Game, player_id: u8) -> Result<Option<Shooter>, CustomError> {
+
+    set_board_status(tx, game.id, player_id, BoardStatus::Placing, BoardStatus::Waiting).await?;
+
+    let boards = lock_boards(tx, game.id).await?;
+    if boards.len() < game.amount_of_players as usize || boards.iter().any(|board| board.status != BoardStatus::Waiting) {
+        return Ok(None);
+    }
+
+    let first = boards.choose(&mut rand::thread_rng())
+        .ok_or(CustomError::InternalServerError)?;
+    let first = Shooter { player_id: first.player_id, user_name: first.user_name.clone() };
+    set_board_status(tx, game.id, first.player_id, BoardStatus::Waiting, BoardStatus::Shooting).await?;
+
+    let sql = "UPDATE game SET started = CURRENT_TIMESTAMP WHERE id = ?";
+    sqlx::query(sql)
+        .bind(game.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error starting game {}: {:?}", game.id, err);
+            CustomError::InternalServerError
+        })?;
+
+    notification::queue_for_user(tx, &first.user_name, game.id, Event::YourTurn).await?;
+    turn_started(tx, game, &first).await?;
+
+    info!("Game {} started, player {} shoots first", game.id, first.player_id);
+    Ok(Some(first))
+}
+
+// The shooter fired a shot which sunk the last ship of the eliminated players. Eliminated players have lost,
+// when the shooter is the only one left he has won and the game is finished. With salvo rules the shooter keeps
+// the turn until he fired one shot for every ship he has afloat. Otherwise the turn passes on to the returned player.
+pub async fn shot_fired(tx: &mut Transaction<'_, MySql>, game: &Game, shooter: u8, eliminated: &[u8]) -> Result<Option<Shooter>, CustomError> {
+
+    for player_id in eliminated {
+        set_board_status(tx, game.id, *player_id, BoardStatus::Waiting, BoardStatus::Lost).await?;
+        info!("Player {} in game {} lost", player_id, game.id);
+    }
+
+    let boards = lock_boards(tx, game.id).await?;
+
+    if is_won(&boards) {
+        set_board_status(tx, game.id, shooter, BoardStatus::Shooting, BoardStatus::Won).await?;
+        set_game_status(tx, game.id, GameStatus::Active, GameStatus::Finished).await?;
+        notification::queue_for_players(tx, game.id, Event::GameOver, "", "").await?;
+        job::cancel(tx, game.id).await?;
+        info!("Game {} won by player {}", game.id, shooter);
+        return Ok(None);
+    }
+
+    let board = boards.iter()
+        .find(|board| board.player_id == shooter)
+        .ok_or(CustomError::InternalServerError)?;
+
+    // The turn goes on, the timeout still counts from the start of the turn
+    if game.rules.salvo && board.turn_shots < ships_afloat(tx, game.id, &board.user_name).await? {
+        if board.user_name == SERVER_USER {
+            job::enqueue(&mut *tx, &Task::AiMove { game_id: game.id, player_id: shooter }, Utc::now()).await?;
+        }
+        return Ok(Some(Shooter { player_id: shooter, user_name: board.user_name.clone() }));
+    }
+
+    pass_turn(tx, game, &boards, shooter).await.map(Some)
+}
+
+// The player didn't shoot within the turn timeout of the rules, the turn passes on. Nothing happens when it isn't
+// the turn of the player anymore
+pub async fn turn_timed_out(tx: &mut Transaction<'_, MySql>, game: &Game, player_id: u8) -> Result<Option<Shooter>, CustomError> {
+
+    if game.status != GameStatus::Active {
+        return Ok(None);
+    }
+
+    let boards = lock_boards(tx, game.id).await?;
+    if !boards.iter().any(|board| board.player_id == player_id && board.status == BoardStatus::Shooting) {
+        return Ok(None);
+    }
+
+    info!("Turn of player {} in game {} timed out", player_id, game.id);
+    pass_turn(tx, game, &boards, player_id).await.map(Some)
+}
+
+// The turn of the shooter is over, the next player that is still in the game gets the turn
+async fn pass_turn(tx: &mut Transaction<'_, MySql>, game: &Game, boards: &[Board], shooter: u8) -> Result<Shooter, CustomError> {
+
+    let sql = "UPDATE board SET turn_shots = 0 WHERE game_id = ? AND player_id = ?";
+    sqlx::query(sql)
+        .bind(game.id)
+        .bind(shooter)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error resetting turn shots: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let next = next_player(boards, shooter).ok_or(CustomError::InternalServerError)?;
+    set_board_status(tx, game.id, shooter, BoardStatus::Shooting, BoardStatus::Waiting).await?;
+    set_board_status(tx, game.id, next, BoardStatus::Waiting, BoardStatus::Shooting).await?;
+
+    let user_name = boards.iter()
+        .find(|board| board.player_id == next)
+        .map(|board| board.user_name.clone())
+        .unwrap_or_default();
+    notification::queue_for_user(tx, &user_name, game.id, Event::YourTurn).await?;
+
+    let next = Shooter { player_id: next, user_name };
+    turn_started(tx, game, &next).await?;
+    Ok(next)
+}
+
+// Replace the jobs of the previous turn: the server takes its turn right away, other players get the turn timeout
+async fn turn_started(tx: &mut Transaction<'_, MySql>, game: &Game, shooter: &Shooter) -> Result<(), CustomError> {
+
+    job::cancel(tx, game.id).await?;
+
+    if shooter.user_name == SERVER_USER {
+        job::enqueue(&mut *tx, &Task::AiMove { game_id: game.id, player_id: shooter.player_id }, Utc::now()).await?;
+    } else if let Some(timeout) = game.rules.turn_timeout {
+        let run_at = Utc::now() + Duration::seconds(timeout as i64);
+        job::enqueue(&mut *tx, &Task::TurnTimeout { game_id: game.id, player_id: shooter.player_id }, run_at).await?;
+    }
+    Ok(())
+}
+
+// The game is aborted, the boards keep their status
+pub async fn abort_game(tx: &mut Transaction<'_, MySql>, game: &Game) -> Result<(), CustomError> {
+
+    set_game_status(tx, game.id, game.status, GameStatus::Aborted).await?;
+    notification::queue_for_players(tx, game.id, Event::GameAborted, "", "").await?;
+    job::cancel(tx, game.id).await?;
+    info!("Game {} aborted", game.id);
+    Ok(())
+}
+
+// The server is killed, all active games are aborted. Returns the ids of the games aborted
+pub async fn abort_all_games(tx: &mut Transaction<'_, MySql>) -> Result<Vec<u32>, CustomError> {
+
+    let sql = "SELECT id FROM game WHERE status = ? FOR UPDATE";
+    let active: Vec<u32> = sqlx::query_scalar(sql)
+        .bind(GameStatus::Active)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error fetching active games: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    for game_id in &active {
+        notification::queue_for_players(tx, *game_id, Event::GameAborted, "", "").await?;
+        job::cancel(tx, *game_id).await?;
+
+        let sql = "UPDATE game SET status = ?, finished = CURRENT_TIMESTAMP WHERE id = ?";
+        sqlx::query(sql)
+            .bind(GameStatus::Aborted)
+            .bind(game_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|err| {
+                error!("Error aborting game {}: {:?}", game_id, err);
+                CustomError::InternalServerError
+            })?;
+    }
+
+    info!("{} active games aborted", active.len());
+    Ok(active)
+}
+
+// The game is won when only one player has not lost
+fn is_won(boards: &[Board]) -> bool {
+    boards.iter().filter(|board| board.status != BoardStatus::Lost).count() == 1
+}
+
+// Select the next player round-robin on player_id, skipping players that are not waiting for their turn
+pub fn next_player(boards: &[Board], current: u8) -> Option<u8> {
+
+    let amount = boards.len() as u8;
+    (1..=amount)
+        .map(|offset| (current - 1 + offset) % amount + 1)
+        .find(|player_id| boards.iter().any(|board| board.player_id == *player_id && board.status == BoardStatus::Waiting))
+}
+
+// The number of ships of a player that are not sunk
+async fn ships_afloat(tx: &mut Transaction<'_, MySql>, game_id: u32, user_name: &str) -> Result<u8, CustomError> {
+
+    let sql = "SELECT ship.*, ship_class.size FROM ship INNER JOIN ship_class ON ship.class = ship_class.name WHERE ship.game_id = ? AND ship.user_name = ?";
+    let ships: Vec<Ship> = sqlx::query_as(sql)
+        .bind(game_id)
+        .bind(user_name)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error fetching ships: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(ships.iter().filter(|ship| !ship.is_sunk()).count() as u8)
+}
+
+async fn set_board_status(tx: &mut Transaction<'_, MySql>, game_id: u32, player_id: u8, from: BoardStatus, to: BoardStatus) -> Result<(), CustomError> {
+
+    if !from.can_become(to) {
+        error!("Illegal board transition {:?} -> {:?} for player {} in game {}", from, to, player_id, game_id);
+        return Err(CustomError::InvalidTransition);
+    }
+
+    let sql = "UPDATE board SET status = ? WHERE game_id = ? AND player_id = ? AND status = ?";
+    let result = sqlx::query(sql)
+        .bind(to)
+        .bind(game_id)
+        .bind(player_id)
+        .bind(from)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error updating board status: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    if result.rows_affected() != 1 {
+        error!("Board of player {} in game {} is not {:?}", player_id, game_id, from);
+        return Err(CustomError::InvalidTransition);
+    }
+    Ok(())
+}
+
+async fn set_game_status(tx: &mut Transaction<'_, MySql>, game_id: u32, from: GameStatus, to: GameStatus) -> Result<(), CustomError> {
+
+    if !from.can_become(to) {
+        error!("Illegal game transition {:?} -> {:?} for game {}", from, to, game_id);
+        return Err(CustomError::InvalidTransition);
+    }
+
+    let sql = "UPDATE game SET status = ?, finished = CURRENT_TIMESTAMP WHERE id = ? AND status = ?";
+    let result = sqlx::query(sql)
+        .bind(to)
+        .bind(game_id)
+        .bind(from)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error updating game status: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    if result.rows_affected() != 1 {
+        error!("Game {} is not {:?}", game_id, from);
+        return Err(CustomError::InvalidTransition);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_status(statuses: &[BoardStatus]) -> Vec<Board> {
+        statuses.iter().enumerate().map(|(i, status)| Board {
+            game_id: 1,
+            user_name: format!("player{}", i + 1),
+            player_id: i as u8 + 1,
+            status: *status,
+            shots_fired: 0,
+            shots_map: Vec::new(),
+            score: 0,
+            turn_shots: 0,
+        }).collect()
+    }
+
+    #[test]
+    fn turn_passes_round_robin() {
+        use BoardStatus::*;
+        let boards = with_status(&[Shooting, Waiting, Waiting]);
+        assert_eq!(next_player(&boards, 1), Some(2));
+        let boards = with_status(&[Waiting, Shooting, Waiting]);
+        assert_eq!(next_player(&boards, 2), Some(3));
+        let boards = with_status(&[Waiting, Waiting, Shooting]);
+        assert_eq!(next_player(&boards, 3), Some(1));
+    }
+
+    #[test]
+    fn players_that_lost_are_skipped() {
+        use BoardStatus::*;
+        let boards = with_status(&[Shooting, Lost, Waiting, Lost]);
+        assert_eq!(next_player(&boards, 1), Some(3));
+        let boards = with_status(&[Waiting, Lost, Shooting, Lost]);
+        assert_eq!(next_player(&boards, 3), Some(1));
+        let boards = with_status(&[Shooting, Lost, Lost]);
+        assert_eq!(next_player(&boards, 1), None);
+    }
+
+    #[test]
+    fn game_is_won_by_the_last_player_standing() {
+        use BoardStatus::*;
+        assert!(!is_won(&with_status(&[Shooting, Waiting, Lost])));
+        assert!(is_won(&with_status(&[Shooting, Lost, Lost])));
+        assert!(is_won(&with_status(&[Lost, Shooting])));
+    }
+
+    #[test]
+    fn boards_only_make_legal_transitions() {
+        use BoardStatus::*;
+        assert!(Placing.can_become(Waiting));
+        assert!(Waiting.can_become(Shooting));
+        assert!(Shooting.can_become(Won));
+        assert!(!Placing.can_become(Shooting));
+        assert!(!Lost.can_become(Waiting));
+        assert!(!Won.can_become(Shooting));
+        assert!(GameStatus::Active.can_become(GameStatus::Finished));
+        assert!(!GameStatus::Aborted.can_become(GameStatus::Finished));
+    }
+}
@@ -0,0 +1,173 @@
+use sqlx::{Executor, MySql, MySqlPool, Transaction};
+use log::{error, info};
+use chrono::{Duration, Utc};
+use rand::{RngCore, rngs::OsRng};
+use crate::models::verification::*;
+use crate::errors::CustomError;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Verification codes for actions that have to be confirmed by mail. A code is drawn from the OS random generator,
+// expires after a time depending on its purpose and is removed when it is used or after too many wrong attempts.
+// Requesting a new code for the same purpose replaces the pending one. A signup that is never verified is removed
+// with its code, so the name and address can be used again.
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Wrong attempts after which a code is removed
+const MAX_ATTEMPTS: u8 = 5;
+
+impl Purpose {
+    // How long a code is valid
+    fn time_to_live(&self) -> Duration {
+        match self {
+            Purpose::Signup => Duration::hours(24),
+            Purpose::PasswordChange => Duration::minutes(15),
+            Purpose::PasswordReset => Duration::minutes(15),
+            Purpose::EmailChange => Duration::hours(24),
+        }
+    }
+}
+
+// Create a code for the user, on the pool or within the transaction of the caller. Returns the code to mail
+pub async fn create<'e, E>(executor: E, user_name: &str, purpose: Purpose, payload: &str) -> Result<u32, CustomError>
+    where E: Executor<'e, Database = MySql> {
+
+    // Zero is never a valid code
+    let code = OsRng.next_u32().max(1);
+    let now = Utc::now();
+
+    let sql = "REPLACE INTO verification (user_name, purpose, code, payload, created, expires, attempts) VALUES (?, ?, ?, ?, ?, ?, 0)";
+    sqlx::query(sql)
+        .bind(user_name)
+        .bind(purpose)
+        .bind(code)
+        .bind(payload)
+        .bind(now)
+        .bind(now + purpose.time_to_live())
+        .execute(executor)
+        .await
+        .map_err(|err| {
+            error!("Error storing verification code: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    Ok(code)
+}
+
+// Check a code of the user. When it is correct the code is removed and its payload is returned. A wrong code counts
+// as an attempt, an expired code or a code with too many attempts is removed. All failures give VerificationFailure.
+pub async fn verify(pool: &MySqlPool, user_name: &str, purpose: Purpose, code: u32) -> Result<String, CustomError> {
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(|err| {
+            error!("Error verifying code: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    let sql = "SELECT * FROM verification WHERE user_name = ? AND purpose = ? FOR UPDATE";
+    let pending: VerificationCode = sqlx::query_as(sql)
+        .bind(user_name)
+        .bind(purpose)
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error fetching verification code: {:?}", err);
+            CustomError::InternalServerError
+        })?
+        .ok_or_else(|| {
+            info!("No pending {:?} verification for {}", purpose, user_name);
+            CustomError::VerificationFailure
+        })?;
+
+    let expired = pending.expires <= Utc::now();
+    let correct = !expired && pending.code == code;
+    let removed = correct || expired || pending.attempts + 1 >= MAX_ATTEMPTS;
+    let sql = if removed {
+        "DELETE FROM verification WHERE user_name = ? AND purpose = ?"
+    } else {
+        "UPDATE verification SET attempts = attempts + 1 WHERE user_name = ? AND purpose = ?"
+    };
+    sqlx::query(sql)
+        .bind(user_name)
+        .bind(purpose)
+        .execute(&mut tx)
+        .await
+        .map_err(|err| {
+            error!("Error updating verification code: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    // Without its code the signup can't be verified anymore
+    if removed && !correct && purpose == Purpose::Signup {
+        remove_unverified(&mut tx, user_name).await?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|err| {
+            error!("Error verifying code: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    if !correct {
+        error!("{:?} verification for {} created at {} failed (expired: {}, attempt {})",
+               pending.purpose, pending.user_name, pending.created, expired, pending.attempts + 1);
+        return Err(CustomError::VerificationFailure);
+    }
+    Ok(pending.payload)
+}
+
+// Remove the signups for the name or address that were never verified and have no live code, so they can be used
+// for a new signup
+pub async fn discard_stale_signups(tx: &mut Transaction<'_, MySql>, user_name: &str, email_address: &str) -> Result<(), CustomError> {
+
+    let sql = "SELECT user.name FROM user LEFT JOIN verification ON verification.user_name = user.name AND verification.purpose = ? AND verification.expires > ? \
+               WHERE (user.name = ? OR user.email_address = ?) AND user.active = false AND user.password_hash = '' AND verification.user_name IS NULL FOR UPDATE";
+    let stale: Vec<String> = sqlx::query_scalar(sql)
+        .bind(Purpose::Signup)
+        .bind(Utc::now())
+        .bind(user_name)
+        .bind(email_address)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error looking up stale signups: {:?}", err);
+            CustomError::InternalServerError
+        })?;
+
+    for name in stale {
+        remove_unverified(tx, &name).await?;
+    }
+    Ok(())
+}
+
+// Remove a user that signed up but never verified, with the rows that refer to it. Other users are left alone
+async fn remove_unverified(tx: &mut Transaction<'_, MySql>, user_name: &str) -> Result<(), CustomError> {
+
+    let sql = "SELECT COUNT(*) FROM user WHERE name = ? AND active = false AND password_hash = '' FOR UPDATE";
+    let (unverified,): (i64,) = sqlx::query_as(sql)
+        .bind(user_name)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|err| {
+            error!("Error looking up signup of {}: {:?}", user_name, err);
+            CustomError::InternalServerError
+        })?;
+    if unverified == 0 {
+        return Ok(());
+    }
+
+    for sql in ["DELETE FROM verification WHERE user_name = ?", "DELETE FROM lockout WHERE user_name = ?", "DELETE FROM user WHERE name = ?"] {
+        sqlx::query(sql)
+            .bind(user_name)
+            .execute(&mut *tx)
+            .await
+            .map_err(|err| {
+                error!("Error removing signup of {}: {:?}", user_name, err);
+                CustomError::InternalServerError
+            })?;
+    }
+
+    info!("Unverified signup of {} removed", user_name);
+    Ok(())
+}